@@ -0,0 +1,537 @@
+// Persistent cache of piece hashes, so repeated runs don't re-read unchanged files.
+// Entries are keyed by device, inode, size and mtime of a file,
+// and by the position, size and hash function of a piece within it.
+// The database is a single append-only file of records.
+// Each record is appended with a single write under a file lock,
+// so runs sharing a cache don't interleave their records.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Result as IOResult, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use super::link::inode;
+use super::{Extent, PieceHash};
+
+const MAGIC: &[u8] = b"find-torrent-data cache 1\n";
+
+// Records with longer paths are corrupt.
+const MAX_PATH_LEN: usize = 1 << 16;
+
+// Identity of a file's content on disk.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FileId {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl FileId {
+    // Inodes are not exposed on all platforms, caching is disabled there.
+    // So is caching for files modified before the epoch.
+    fn new(meta: &Metadata) -> Option<FileId> {
+        let (dev, ino) = inode(meta)?;
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(FileId {
+            dev,
+            ino,
+            size: meta.len(),
+            mtime: mtime.as_secs() as i64,
+            mtime_nsec: mtime.subsec_nanos() as i64,
+        })
+    }
+}
+
+// Position and hash function of a piece within a file.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct PieceKey {
+    offset: i64,
+    size: i64,
+    padding: i64,
+    // 1 for SHA-1, 2 for SHA-256 merkle trees
+    algorithm: u8,
+    leaves: u64,
+}
+
+impl PieceKey {
+    pub fn new(extent: &Extent) -> PieceKey {
+        let (algorithm, leaves) = match extent.hash {
+            PieceHash::Sha1(_) => (1, 0),
+            PieceHash::Merkle(_, leaves) => (2, leaves as u64),
+        };
+        PieceKey {
+            offset: extent.offset,
+            size: extent.size,
+            padding: extent.padding,
+            algorithm,
+            leaves,
+        }
+    }
+}
+
+// Cached hashes of a single file.
+struct Entry {
+    id: FileId,
+    path: PathBuf,
+    pieces: HashMap<PieceKey, Vec<u8>>,
+}
+
+impl Entry {
+    fn new(id: FileId, path: PathBuf) -> Entry {
+        Entry {
+            id,
+            path,
+            pieces: HashMap::new(),
+        }
+    }
+}
+
+pub struct Cache {
+    path: PathBuf,
+    working_dir: PathBuf,
+    // Entries by device and inode
+    entries: Mutex<HashMap<(u64, u64), Entry>>,
+    // Length of the database up to the last complete record, as of loading it
+    loaded_len: u64,
+    log: Mutex<Option<Log>>,
+}
+
+// Database opened for appending records.
+struct Log {
+    file: File,
+    // End of the last complete record
+    end: u64,
+}
+
+impl Log {
+    // Appends a record, after dropping a record cut short by a crash.
+    // Records appended by other runs since the last append are kept.
+    fn append(&mut self, record: &[u8]) -> IOResult<()> {
+        self.file.lock()?;
+        let result = (|| {
+            let len = self.file.metadata()?.len();
+            if len == 0 {
+                self.file.write_all(MAGIC)?;
+                self.end = MAGIC.len() as u64;
+            } else if len != self.end {
+                // Rescan from the start if the database shrunk under us
+                let from = if len < self.end {
+                    MAGIC.len() as u64
+                } else {
+                    self.end
+                };
+                self.end = complete_len(&self.file, from)?;
+                if self.end != len {
+                    self.file.set_len(self.end)?;
+                }
+            }
+            self.file.write_all(record)?;
+            self.end += record.len() as u64;
+            Ok(())
+        })();
+        self.file.unlock()?;
+        result
+    }
+}
+
+// Returns the end of the last complete record after `from`.
+fn complete_len(file: &File, from: u64) -> IOResult<u64> {
+    let mut reader = file.try_clone()?;
+    reader.seek(SeekFrom::Start(from))?;
+    let mut reader = BufReader::new(reader);
+    let mut end = from;
+    while let Some((_, len)) = read_record(&mut reader)? {
+        end += len;
+    }
+    Ok(end)
+}
+
+impl Cache {
+    // Loads the cache at `path`, creating it if it doesn't exist or is empty.
+    pub fn open(path: &Path) -> IOResult<Cache> {
+        let mut entries = HashMap::new();
+        let mut loaded_len = 0;
+        match File::open(path) {
+            // A crash may leave the database created but empty
+            Ok(ref file) if file.metadata().is_ok_and(|meta| meta.len() == 0) => {}
+            Ok(file) => {
+                let mut reader = BufReader::new(file);
+                let mut magic = vec![0u8; MAGIC.len()];
+                reader.read_exact(&mut magic)?;
+                if magic != MAGIC {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{} is not a hash cache", path.display()),
+                    ));
+                }
+                // Later records replace earlier ones,
+                // a record cut short by a crash ends the log and is dropped by the next append.
+                loaded_len = MAGIC.len() as u64;
+                while let Some(((id, path, piece, digest), len)) = read_record(&mut reader)? {
+                    entry_mut(&mut entries, id, path)
+                        .pieces
+                        .insert(piece, digest);
+                    loaded_len += len;
+                }
+            }
+            Err(ref err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(Cache {
+            path: path.to_path_buf(),
+            working_dir: std::env::current_dir()?,
+            entries: Mutex::new(entries),
+            loaded_len,
+            log: Mutex::new(None),
+        })
+    }
+
+    pub fn get(&self, meta: &Metadata, piece: &PieceKey) -> Option<Vec<u8>> {
+        let id = FileId::new(meta)?;
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&(id.dev, id.ino))?;
+        if entry.id != id {
+            return None;
+        }
+        entry.pieces.get(piece).cloned()
+    }
+
+    // Stores the digest of a piece.
+    // Entries of a file whose size or mtime changed are dropped.
+    pub fn insert(
+        &self,
+        path: &Path,
+        meta: &Metadata,
+        piece: PieceKey,
+        digest: Vec<u8>,
+    ) -> IOResult<()> {
+        let id = match FileId::new(meta) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        // Pruning looks files up by path, so it must not depend on the working directory
+        let path = self.working_dir.join(path);
+        let mut log = self.log.lock().unwrap();
+        if log.is_none() {
+            *log = Some(self.open_log()?);
+        }
+        log.as_mut()
+            .unwrap()
+            .append(&encode_record(&id, &path, &piece, &digest))?;
+        let mut entries = self.entries.lock().unwrap();
+        entry_mut(&mut entries, id, path)
+            .pieces
+            .insert(piece, digest);
+        Ok(())
+    }
+
+    // Drops entries of files that were removed or changed since they were hashed,
+    // and rewrites the database without them.
+    // Returns the number of files kept and removed.
+    pub fn prune(&self) -> IOResult<(usize, usize)> {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|_, entry| {
+            fs::metadata(&entry.path)
+                .ok()
+                .and_then(|meta| FileId::new(&meta))
+                .is_some_and(|id| id == entry.id)
+        });
+        // Write to a temporary file and replace the database with it
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(MAGIC)?;
+        for entry in entries.values() {
+            for (piece, digest) in &entry.pieces {
+                writer.write_all(&encode_record(&entry.id, &entry.path, piece, digest))?;
+            }
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        *self.log.lock().unwrap() = None;
+        Ok((entries.len(), before - entries.len()))
+    }
+
+    fn open_log(&self) -> IOResult<Log> {
+        // Readable to find the last complete record
+        let file = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        Ok(Log {
+            file,
+            end: self.loaded_len,
+        })
+    }
+}
+
+// Returns the entry of a file, replacing it if the file changed.
+// The last path a file was seen at is kept.
+fn entry_mut(entries: &mut HashMap<(u64, u64), Entry>, id: FileId, path: PathBuf) -> &mut Entry {
+    let entry = entries
+        .entry((id.dev, id.ino))
+        .or_insert_with(|| Entry::new(id, path.clone()));
+    if entry.id != id {
+        *entry = Entry::new(id, path);
+    } else {
+        entry.path = path;
+    }
+    entry
+}
+
+// Records are little endian:
+// dev, ino, size, mtime, mtime_nsec, offset, piece size, padding, leaves (8 bytes each),
+// algorithm (1 byte), digest length (1 byte), digest,
+// path length (4 bytes), path.
+fn encode_record(id: &FileId, path: &Path, piece: &PieceKey, digest: &[u8]) -> Vec<u8> {
+    let path = path_to_bytes(path);
+    let mut record = Vec::with_capacity(80 + digest.len() + path.len());
+    for &n in &[id.dev, id.ino, id.size] {
+        record.extend_from_slice(&n.to_le_bytes());
+    }
+    for &n in &[
+        id.mtime,
+        id.mtime_nsec,
+        piece.offset,
+        piece.size,
+        piece.padding,
+    ] {
+        record.extend_from_slice(&n.to_le_bytes());
+    }
+    record.extend_from_slice(&piece.leaves.to_le_bytes());
+    record.push(piece.algorithm);
+    record.push(digest.len() as u8);
+    record.extend_from_slice(digest);
+    record.extend_from_slice(&(path.len() as u32).to_le_bytes());
+    record.extend_from_slice(&path);
+    record
+}
+
+type Record = (FileId, PathBuf, PieceKey, Vec<u8>);
+
+// Returns the next record and its length.
+// Records that are cut short or implausible end the log.
+fn read_record<R: Read>(r: &mut R) -> IOResult<Option<(Record, u64)>> {
+    let mut fixed = [0u8; 74];
+    match r.read_exact(&mut fixed) {
+        Ok(()) => {}
+        Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let word = |i: usize| -> [u8; 8] { fixed[i * 8..i * 8 + 8].try_into().unwrap() };
+    let id = FileId {
+        dev: u64::from_le_bytes(word(0)),
+        ino: u64::from_le_bytes(word(1)),
+        size: u64::from_le_bytes(word(2)),
+        mtime: i64::from_le_bytes(word(3)),
+        mtime_nsec: i64::from_le_bytes(word(4)),
+    };
+    let piece = PieceKey {
+        offset: i64::from_le_bytes(word(5)),
+        size: i64::from_le_bytes(word(6)),
+        padding: i64::from_le_bytes(word(7)),
+        leaves: u64::from_le_bytes(word(8)),
+        algorithm: fixed[72],
+    };
+    let digest_len = match piece.algorithm {
+        1 => 20,
+        2 => 32,
+        _ => return Ok(None),
+    };
+    if fixed[73] as usize != digest_len {
+        return Ok(None);
+    }
+    let mut digest = vec![0u8; digest_len];
+    let mut path_len = [0u8; 4];
+    let mut path = Vec::new();
+    let rest = r
+        .read_exact(&mut digest)
+        .and_then(|_| r.read_exact(&mut path_len))
+        .and_then(|_| {
+            let len = u32::from_le_bytes(path_len) as usize;
+            if len == 0 || len > MAX_PATH_LEN {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            path.resize(len, 0);
+            r.read_exact(&mut path)
+        });
+    match rest {
+        Ok(()) => {
+            let len = (fixed.len() + digest.len() + path_len.len() + path.len()) as u64;
+            Ok(Some(((id, path_from_bytes(path), piece, digest), len)))
+        }
+        Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(target_family = "unix")]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(target_family = "unix"))]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(not(target_family = "unix"))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File, OpenOptions};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use super::{Cache, PieceKey, MAGIC};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "find-torrent-data-cache-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn key(offset: i64) -> PieceKey {
+        PieceKey {
+            offset,
+            size: 16384,
+            padding: 0,
+            algorithm: 1,
+            leaves: 0,
+        }
+    }
+
+    // Digest of the piece at `offset` in `file`, as cached.
+    fn get(cache: &Cache, file: &Path, offset: i64) -> Option<Vec<u8>> {
+        cache.get(&fs::metadata(file).unwrap(), &key(offset))
+    }
+
+    fn insert(cache: &Cache, file: &Path, offset: i64, digest: u8) {
+        let meta = fs::metadata(file).unwrap();
+        cache
+            .insert(file, &meta, key(offset), vec![digest; 20])
+            .unwrap();
+    }
+
+    #[test]
+    fn records_survive_reopening() {
+        let dir = temp_dir("reopen");
+        let (db, file) = (dir.join("db"), dir.join("file"));
+        fs::write(&file, vec![1u8; 32768]).unwrap();
+        let cache = Cache::open(&db).unwrap();
+        insert(&cache, &file, 0, 1);
+        insert(&cache, &file, 16384, 2);
+        // A later record for the same piece replaces the earlier one
+        insert(&cache, &file, 16384, 3);
+        drop(cache);
+
+        let cache = Cache::open(&db).unwrap();
+        assert_eq!(get(&cache, &file, 0), Some(vec![1; 20]));
+        assert_eq!(get(&cache, &file, 16384), Some(vec![3; 20]));
+        assert_eq!(get(&cache, &file, 32768), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drops_a_truncated_record() {
+        let dir = temp_dir("truncated");
+        let (db, file) = (dir.join("db"), dir.join("file"));
+        fs::write(&file, vec![1u8; 32768]).unwrap();
+        let cache = Cache::open(&db).unwrap();
+        insert(&cache, &file, 0, 1);
+        insert(&cache, &file, 16384, 2);
+        drop(cache);
+        // A crash cut the last record short
+        let len = fs::metadata(&db).unwrap().len();
+        let record_len = (len - MAGIC.len() as u64) / 2;
+        let db_file = OpenOptions::new().write(true).open(&db).unwrap();
+        db_file.set_len(len - 5).unwrap();
+        drop(db_file);
+
+        let cache = Cache::open(&db).unwrap();
+        assert_eq!(get(&cache, &file, 0), Some(vec![1; 20]));
+        assert_eq!(get(&cache, &file, 16384), None);
+        // The next append replaces the partial record
+        insert(&cache, &file, 32768, 3);
+        drop(cache);
+        assert_eq!(fs::metadata(&db).unwrap().len(), len);
+
+        let cache = Cache::open(&db).unwrap();
+        assert_eq!(get(&cache, &file, 0), Some(vec![1; 20]));
+        assert_eq!(get(&cache, &file, 32768), Some(vec![3; 20]));
+        assert_eq!(
+            fs::metadata(&db).unwrap().len(),
+            MAGIC.len() as u64 + 2 * record_len
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn changed_files_miss() {
+        let dir = temp_dir("changed");
+        let (db, file) = (dir.join("db"), dir.join("file"));
+        fs::write(&file, vec![1u8; 16384]).unwrap();
+        let cache = Cache::open(&db).unwrap();
+        insert(&cache, &file, 0, 1);
+        assert_eq!(get(&cache, &file, 0), Some(vec![1; 20]));
+
+        // Same size, different mtime
+        let mtime = fs::metadata(&file).unwrap().modified().unwrap();
+        let f = File::options().write(true).open(&file).unwrap();
+        f.set_modified(mtime + Duration::from_secs(1)).unwrap();
+        drop(f);
+        assert_eq!(get(&cache, &file, 0), None);
+
+        // Different size, same mtime
+        insert(&cache, &file, 0, 2);
+        let f = File::options().write(true).open(&file).unwrap();
+        f.set_len(8192).unwrap();
+        f.set_modified(mtime + Duration::from_secs(1)).unwrap();
+        drop(f);
+        assert_eq!(get(&cache, &file, 0), None);
+        drop(cache);
+
+        // Entries of the old content are kept on disk, but still miss
+        let cache = Cache::open(&db).unwrap();
+        assert_eq!(get(&cache, &file, 0), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn empty_database_is_fresh() {
+        let dir = temp_dir("empty");
+        let (db, file) = (dir.join("db"), dir.join("file"));
+        fs::write(&file, vec![1u8; 16384]).unwrap();
+        File::create(&db).unwrap();
+        let cache = Cache::open(&db).unwrap();
+        insert(&cache, &file, 0, 1);
+        drop(cache);
+
+        let cache = Cache::open(&db).unwrap();
+        assert_eq!(get(&cache, &file, 0), Some(vec![1; 20]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
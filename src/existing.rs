@@ -0,0 +1,58 @@
+// Files already in place in the output, from a previous run or another tool.
+// They are verified before the input walk, so only missing files are searched for.
+
+use std::fs::File;
+use std::path::Path;
+
+use super::cache::Cache;
+use super::{Descriptor, Span};
+
+// Returns which files at the path of a descriptor match their own pieces,
+// and which of them are satisfied, because their pieces shared with other files match too.
+// Pieces of their own are checked as far as `threshold` asks.
+pub fn verify(
+    descriptors: &[Descriptor],
+    spans: &[Span],
+    threshold: f32,
+    cache: Option<&Cache>,
+) -> (Vec<bool>, Vec<bool>) {
+    let intact: Vec<bool> = descriptors
+        .iter()
+        .map(|d| verify_file(d, threshold, cache).unwrap_or(false))
+        .collect();
+    let mut present = intact.clone();
+    for span in spans {
+        let verified = span.parts.iter().all(|p| intact[p.file]) && {
+            let paths: Vec<&Path> = span
+                .parts
+                .iter()
+                .map(|p| descriptors[p.file].path.as_path())
+                .collect();
+            span.verify(&paths).unwrap_or(false)
+        };
+        if !verified {
+            for part in &span.parts {
+                present[part.file] = false;
+            }
+        }
+    }
+    (intact, present)
+}
+
+fn verify_file(
+    descriptor: &Descriptor,
+    threshold: f32,
+    cache: Option<&Cache>,
+) -> std::io::Result<bool> {
+    let mut file = match File::open(&descriptor.path) {
+        Ok(file) => file,
+        Err(_) => return Ok(false),
+    };
+    if file.metadata()?.len() != descriptor.size as u64 {
+        return Ok(false);
+    }
+    let cache = cache.map(|cache| (cache, descriptor.path.as_path()));
+    Ok(descriptor
+        .verify_file(&mut file, threshold, cache)?
+        .is_none())
+}
@@ -0,0 +1,366 @@
+// Ways of placing a source file at its path in the torrent layout.
+
+use std::fs::{self, hard_link, File, OpenOptions};
+use std::io::{Error as IOError, ErrorKind, Read, Result as IOResult};
+use std::path::{Path, PathBuf};
+
+// Checks the content of a copied file, before it is moved into place.
+pub type Verify<'a> = &'a dyn Fn(&Path) -> IOResult<bool>;
+
+// How symbolic links refer to their source.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkTarget {
+    // The source path as found while walking the input
+    #[default]
+    AsFound,
+    // Relative to the directory of the link, so trees can be moved together
+    Relative,
+    // Canonical absolute path, independent of the working directory
+    Absolute,
+}
+
+impl SymlinkTarget {
+    pub fn name(self) -> &'static str {
+        match self {
+            SymlinkTarget::AsFound => "as_found",
+            SymlinkTarget::Relative => "relative",
+            SymlinkTarget::Absolute => "absolute",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SymlinkTarget> {
+        [
+            SymlinkTarget::AsFound,
+            SymlinkTarget::Relative,
+            SymlinkTarget::Absolute,
+        ]
+        .iter()
+        .copied()
+        .find(|target| target.name() == name)
+    }
+
+    // Path a symbolic link at `want` should contain to point to `is`.
+    pub fn resolve(self, is: &Path, want: &Path) -> IOResult<PathBuf> {
+        match self {
+            SymlinkTarget::AsFound => Ok(is.to_path_buf()),
+            SymlinkTarget::Absolute => fs::canonicalize(is),
+            SymlinkTarget::Relative => {
+                let is = canonicalize_existing(is)?;
+                let dir = canonicalize_existing(want.parent().unwrap_or_else(|| Path::new("")))?;
+                let common = is
+                    .components()
+                    .zip(dir.components())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                let mut target = PathBuf::new();
+                for _ in dir.components().skip(common) {
+                    target.push("..");
+                }
+                target.extend(is.components().skip(common));
+                Ok(target)
+            }
+        }
+    }
+}
+
+// Settings for creating output files.
+#[derive(Clone, Copy, Default)]
+pub struct Options<'a> {
+    pub symlink_target: SymlinkTarget,
+    // Checks copies before they are moved into place
+    pub verify: Option<Verify<'a>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Hardlink,
+    Symlink,
+    // Copy-on-write clone sharing extents with the source
+    Reflink,
+    Copy,
+    // Rename within a filesystem, copy and remove the source across filesystems
+    Move,
+    // The first of reflink, hard link, symbolic link and copy that works
+    Auto,
+}
+
+impl Method {
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::Hardlink => "hardlink",
+            Method::Symlink => "symlink",
+            Method::Reflink => "reflink",
+            Method::Copy => "copy",
+            Method::Move => "move",
+            Method::Auto => "auto",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Method> {
+        [
+            Method::Hardlink,
+            Method::Symlink,
+            Method::Reflink,
+            Method::Copy,
+            Method::Move,
+            Method::Auto,
+        ]
+        .iter()
+        .copied()
+        .find(|method| method.name() == name)
+    }
+}
+
+// What to do when a file already exists where a link is to be created.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Conflict {
+    // Keep the existing file if it is the source or matches the torrent
+    Skip,
+    Replace,
+    // Move the existing file to a numbered backup name
+    Rename,
+    Fail,
+}
+
+impl Conflict {
+    pub fn from_name(name: &str) -> Conflict {
+        match name {
+            "skip" => Conflict::Skip,
+            "replace" => Conflict::Replace,
+            "rename" => Conflict::Rename,
+            _ => Conflict::Fail,
+        }
+    }
+}
+
+// Creates `want` from `is`, returns the method used.
+pub fn create(is: &Path, want: &Path, method: Method, opts: &Options) -> IOResult<Method> {
+    match method {
+        Method::Hardlink => hard_link(is, want),
+        Method::Symlink => soft_link(opts.symlink_target.resolve(is, want)?, want),
+        Method::Reflink => reflink(is, want),
+        Method::Copy => copy(is, want, opts.verify),
+        Method::Move => move_file(is, want, opts.verify),
+        Method::Auto => {
+            let mut last_err = None;
+            for &method in &[
+                Method::Reflink,
+                Method::Hardlink,
+                Method::Symlink,
+                Method::Copy,
+            ] {
+                match create(is, want, method, opts) {
+                    Ok(method) => return Ok(method),
+                    // No other method will work either
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists => return Err(e),
+                    Err(e) => last_err = Some(e),
+                }
+            }
+            return Err(last_err.unwrap());
+        }
+    }
+    .map(|_| method)
+}
+
+// Whether a hard link or rename failed because the paths are on different filesystems.
+pub fn is_cross_device(err: &IOError) -> bool {
+    #[cfg(target_family = "unix")]
+    const EXDEV: i32 = 18;
+    // ERROR_NOT_SAME_DEVICE
+    #[cfg(not(target_family = "unix"))]
+    const EXDEV: i32 = 17;
+    err.raw_os_error() == Some(EXDEV)
+}
+
+// Copies through a temporary file next to `want`,
+// which is synced and verified before it is renamed into place,
+// so `want` never holds partial or corrupt data.
+fn copy(is: &Path, want: &Path, verify: Option<Verify>) -> IOResult<()> {
+    if fs::symlink_metadata(want).is_ok() {
+        return Err(IOError::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists", want.display()),
+        ));
+    }
+    let tmp = temp_path(want);
+    // A temporary file left by an interrupted copy is ours to replace
+    let in_tmp = |e: IOError| IOError::new(e.kind(), format!("{}: {}", tmp.display(), e));
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(in_tmp(e)),
+        _ => {}
+    }
+    let result = (|| {
+        let mut src = File::open(is)
+            .map_err(|e| IOError::new(e.kind(), format!("{}: {}", is.display(), e)))?;
+        let mut dst = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp)
+            .map_err(in_tmp)?;
+        std::io::copy(&mut src, &mut dst)?;
+        dst.set_permissions(src.metadata()?.permissions())?;
+        dst.sync_all()?;
+        drop(dst);
+        if let Some(verify) = verify {
+            if !verify(&tmp)? {
+                return Err(IOError::new(
+                    ErrorKind::InvalidData,
+                    format!("Copy of {} does not match the torrent", is.display()),
+                ));
+            }
+        }
+        fs::rename(&tmp, want)?;
+        sync_parent(want)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+// Renames within a filesystem, which is atomic.
+// Across filesystems the source is only removed once its copy is durable and verified,
+// so a crash leaves at least one complete file.
+fn move_file(is: &Path, want: &Path, verify: Option<Verify>) -> IOResult<()> {
+    if fs::symlink_metadata(want).is_ok() {
+        return Err(IOError::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists", want.display()),
+        ));
+    }
+    match fs::rename(is, want) {
+        Err(e) if is_cross_device(&e) => {
+            copy(is, want, verify)?;
+            fs::remove_file(is)
+        }
+        result => result,
+    }
+}
+
+// Whether two files have the same content.
+pub fn same_content(a: &Path, b: &Path) -> IOResult<bool> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let (mut buf_a, mut buf_b) = (vec![0u8; 1 << 16], vec![0u8; 1 << 16]);
+    loop {
+        let n = a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+// Moves `path` to the first free name of the form `name.~N~`, like `cp --backup=numbered`.
+pub fn rename_aside(path: &Path) -> IOResult<PathBuf> {
+    for n in 1.. {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".~{}~", n));
+        let backup = path.with_file_name(name);
+        if fs::symlink_metadata(&backup).is_err() {
+            fs::rename(path, &backup)?;
+            return Ok(backup);
+        }
+    }
+    unreachable!()
+}
+
+// Device and inode number, which identify a file regardless of its links.
+#[cfg(target_family = "unix")]
+pub fn inode(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+// Inodes are not exposed on other platforms.
+#[cfg(not(target_family = "unix"))]
+pub fn inode(_meta: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// Canonicalizes the longest existing prefix of a path and appends the rest,
+// so paths that are yet to be created can be resolved.
+pub fn canonicalize_existing(path: &Path) -> IOResult<PathBuf> {
+    let path = std::env::current_dir()?.join(path);
+    let mut missing = Vec::new();
+    let mut existing = path.as_path();
+    loop {
+        match fs::canonicalize(existing) {
+            Ok(mut resolved) => {
+                resolved.extend(missing.iter().rev());
+                return Ok(resolved);
+            }
+            Err(e) => match (existing.parent(), existing.file_name()) {
+                (Some(parent), Some(name)) => {
+                    missing.push(name);
+                    existing = parent;
+                }
+                _ => return Err(e),
+            },
+        }
+    }
+}
+
+// Hidden file in the directory of `path` to write its content to.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+// Makes a rename into the directory of `path` durable.
+#[cfg(target_family = "unix")]
+fn sync_parent(path: &Path) -> IOResult<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+// Directories can't be opened for syncing on other platforms.
+#[cfg(not(target_family = "unix"))]
+fn sync_parent(_path: &Path) -> IOResult<()> {
+    Ok(())
+}
+
+// Clones a file with the FICLONE ioctl, supported by btrfs, XFS and others.
+#[cfg(target_os = "linux")]
+fn reflink(is: &Path, want: &Path) -> IOResult<()> {
+    use std::os::unix::io::AsRawFd;
+    // _IOW(0x94, 9, int)
+    const FICLONE: u32 = 0x4004_9409;
+    let src = File::open(is)?;
+    let dst = OpenOptions::new().write(true).create_new(true).open(want)?;
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) };
+    if ret == -1 {
+        let err = std::io::Error::last_os_error();
+        drop(dst);
+        let _ = fs::remove_file(want);
+        return Err(err);
+    }
+    dst.set_permissions(src.metadata()?.permissions())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_is: &Path, _want: &Path) -> IOResult<()> {
+    Err(std::io::Error::new(
+        ErrorKind::Other,
+        "Reflinks are only supported on Linux",
+    ))
+}
+
+#[cfg(target_family = "windows")]
+pub fn soft_link<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> IOResult<()> {
+    std::os::windows::fs::symlink_file(src, dst)
+}
+
+#[cfg(target_family = "unix")]
+pub fn soft_link<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> IOResult<()> {
+    std::os::unix::fs::symlink(src, dst)
+}
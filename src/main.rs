@@ -1,18 +1,39 @@
 #[macro_use]
 extern crate clap;
 
-use std::borrow::Borrow;
+mod cache;
+mod existing;
+mod link;
+mod manifest;
+mod names;
+mod pieces;
+mod report;
+mod resume;
+mod sanitize;
+mod search;
+mod solver;
+mod v2;
+
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::fs::{create_dir_all, hard_link, File};
-use std::io::{Read, Result as IOResult, Seek, SeekFrom};
+use std::fs::{create_dir_all, File};
+use std::io::{ErrorKind, Read, Result as IOResult, Seek, SeekFrom};
 use std::iter::Iterator;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::sync::Arc;
 
+use cache::{Cache, PieceKey};
+use lava_torrent::bencode::BencodeElem;
 use lava_torrent::torrent::v1::Torrent;
+use link::{Conflict, Method, SymlinkTarget};
 use multimap::MultiMap;
+use names::{Naming, Policy};
+use pieces::{Completion, PieceState};
+use report::{Format, Report};
+use search::SearchContext;
+use serde_json::json;
 use sha1::{Digest, Sha1};
-use walkdir::WalkDir;
 
 fn main() {
     let cli = clap_app!(myapp =>
@@ -23,30 +44,112 @@ fn main() {
         (@arg input: -i +takes_value +required +multiple "Add search directory")
         (@arg output: -o +takes_value default_value("./") "Output directory")
         (@arg create_symlinks: -s --symlinks "Use symbolic links")
+        (@arg relative_symlinks: --("relative-symlinks") "Use symbolic links relative to their directory")
+        (@arg absolute_symlinks: --("absolute-symlinks") conflicts_with[relative_symlinks] "Use symbolic links to canonical absolute paths")
+        (@arg reflink: --reflink conflicts_with[create_symlinks relative_symlinks absolute_symlinks] "Use copy-on-write clones")
+        (@arg auto_link: --("auto-link") conflicts_with[create_symlinks relative_symlinks absolute_symlinks reflink] "Use the first of clones, hard links, symbolic links and copies that works")
+        (@arg copy: --copy conflicts_with[create_symlinks relative_symlinks absolute_symlinks reflink auto_link] "Copy files and verify the copies")
+        (@arg move: --move conflicts_with[create_symlinks relative_symlinks absolute_symlinks reflink auto_link copy] "Move files, copying and verifying them across filesystems")
+        (@arg copy_fallback: --("copy-fallback") "Copy and verify files that can't be hard linked across filesystems")
         (@arg follow_symlinks: --("follow-symlinks") "Follow symlinks in input")
         (@arg hash: -h +takes_value default_value("1.0") "Fraction of hash pieces to be verified")
-        (@arg TORRENT: +required "Torrent file")
-    ).get_matches();
-    if let Err(e) = run(cli) {
+        (@arg max_combinations: --("max-combinations") +takes_value default_value("10000") "Candidate combinations to try for files without pieces of their own")
+        (@arg cache: --cache +takes_value "Piece hash cache file")
+        (@arg jobs: -j +takes_value "Number of hashing threads per device [default: number of CPUs]")
+        (@arg dry_run: -n --("dry-run") "Print the directories and links to create without creating them")
+        (@arg manifest: --manifest +takes_value "Save the links as a JSON manifest for the apply subcommand")
+        (@arg script: --script +takes_value "Save the links as a shell script")
+        (@arg record: --record +takes_value "Record created directories and links for the undo subcommand")
+        (@arg fastresume: --fastresume "Write libtorrent resume data for the verified pieces to the output directory")
+        (@arg transmission: --transmission "Write Transmission resume data for the verified pieces to the output directory, or next to the torrents directory if given")
+        (@arg transmission_torrents: --("transmission-torrents") +takes_value "Copy torrent files into this Transmission torrents directory, resume data goes into the resume directory beside it")
+        (@arg sanitize: --sanitize +takes_value possible_values(&["strict", "lenient"]) default_value("strict") "Reject torrents with unsafe paths, or rename the unsafe parts")
+        (@arg translate_names: --("translate-names") +takes_value +multiple_occurrences +use_value_delimiter possible_values(&["truncate", "replace", "nfc", "nfd", "case"]) "Rename files for the output filesystem: truncate long names, replace illegal characters, normalize Unicode or rename case collisions")
+        (@arg name_map: --("name-map") +takes_value "Write the renamed files of each torrent to this JSON file")
+        (@arg incremental: --incremental "Keep files in the output that match the torrent and only search for the rest")
+        (@arg on_conflict: --("on-conflict") +takes_value possible_values(&["skip", "replace", "rename", "fail"]) default_value("fail") "What to do with files that already exist in the output: keep them if they match the torrent, replace them, rename them aside or fail")
+        (@arg format: --format +takes_value possible_values(&["text", "json", "ndjson"]) default_value("text") "Output format")
+        (@arg device_jobs: --("device-jobs") +takes_value +multiple_occurrences "Number of hashing threads for the device holding a path, as PATH=N")
+        (@arg TORRENT: +required +multiple "Torrent files or directories containing them")
+        (@subcommand prune =>
+            (about: "Remove entries of deleted or modified files from a hash cache")
+            (@arg CACHE: +required "Piece hash cache file")
+        )
+        (@subcommand apply =>
+            (about: "Create the links of a saved manifest whose sources didn't change")
+            (@arg MANIFEST: +required "Manifest file")
+        )
+        (@subcommand undo =>
+            (about: "Remove the links and empty directories of a record, unless they were replaced, and restore renamed files")
+            (@arg RECORD: +required "Record file")
+        )
+    )
+    .subcommand_negates_reqs(true)
+    .get_matches();
+    let result = if let Some(sub) = cli.subcommand_matches("prune") {
+        prune_cache(sub)
+    } else if let Some(sub) = cli.subcommand_matches("apply") {
+        apply_manifest(sub)
+    } else if let Some(sub) = cli.subcommand_matches("undo") {
+        undo_record(sub)
+    } else {
+        run(cli)
+    };
+    if let Err(e) = result {
         eprintln!("{}", e);
         std::process::exit(1);
     }
 }
 
-macro_rules! unwrap_or_break {
-    ($x:expr) => {
-        match $x {
-            Some(x) => x,
-            None => break,
-        };
-    };
-}
-
+// Piece within a single file.
+// Only the final piece of a torrent is shorter than the piece length.
+// `padding` zero bytes from pad files follow the content of the piece.
 #[derive(Clone)]
 struct Extent {
+    // Index of the piece in the torrent
+    piece: usize,
     offset: i64,
     size: i64,
-    hash: [u8; 20],
+    padding: i64,
+    hash: PieceHash,
+}
+
+// Expected hash of a piece.
+#[derive(Clone)]
+enum PieceHash {
+    // SHA-1 of the piece content (v1)
+    Sha1([u8; 20]),
+    // SHA-256 merkle root over the 16 KiB blocks of the piece,
+    // padded with zero hashes to the given number of leaves (v2)
+    Merkle([u8; 32], usize),
+}
+
+impl PieceHash {
+    // Hash `size` bytes from `reader`.
+    // Returns `None` if fewer bytes could be read.
+    fn digest<R: Read>(&self, reader: R, size: i64) -> IOResult<Option<Vec<u8>>> {
+        match self {
+            PieceHash::Sha1(_) => {
+                let mut state = Sha1::new();
+                let bytes_hashed = std::io::copy(&mut reader.take(size as u64), &mut state)?;
+                if bytes_hashed as i64 != size {
+                    return Ok(None);
+                }
+                Ok(Some(state.result().to_vec()))
+            }
+            PieceHash::Merkle(_, leaves) => {
+                let root = v2::merkle_root(reader, size, *leaves)?;
+                Ok(root.map(|root| root.to_vec()))
+            }
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            PieceHash::Sha1(hash) => hash,
+            PieceHash::Merkle(hash, _) => hash,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -56,33 +159,153 @@ struct Descriptor {
     extents: Vec<Extent>,
 }
 
+// Slice of a torrent file that is covered by a spanning piece,
+// followed by `padding` zero bytes from pad files.
+struct Part {
+    file: usize,
+    offset: i64,
+    size: i64,
+    padding: i64,
+}
+
+// Piece that overlaps two or more files.
+// Parts are in torrent order and reference descriptors by index.
+struct Span {
+    piece: usize,
+    hash: [u8; 20],
+    parts: Vec<Part>,
+}
+
+impl Span {
+    // Verify the concatenation of the parts against the piece hash.
+    // `paths` holds the file chosen for each part, in the same order.
+    fn verify<P: AsRef<Path>>(&self, paths: &[P]) -> IOResult<bool> {
+        let mut state = Sha1::new();
+        for (part, path) in self.parts.iter().zip(paths) {
+            if !part.hash(path, &mut state)? {
+                return Ok(false);
+            }
+        }
+        let hash = state.result();
+        Ok(hash.as_slice() == &self.hash[..])
+    }
+
+    // Bytes hashed to verify the span, including padding.
+    fn size(&self) -> i64 {
+        self.parts.iter().map(|p| p.size + p.padding).sum()
+    }
+}
+
+impl Part {
+    // Feed the part of the file at `path` and its padding into `state`.
+    // Returns false if the file is too short.
+    fn hash<P: AsRef<Path>>(&self, path: P, state: &mut Sha1) -> IOResult<bool> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(self.offset as u64))?;
+        let bytes_hashed = std::io::copy(&mut file.take(self.size as u64), state)?;
+        if bytes_hashed as i64 != self.size {
+            return Ok(false);
+        }
+        std::io::copy(&mut std::io::repeat(0).take(self.padding as u64), state)?;
+        Ok(true)
+    }
+}
+
+// Descriptors of all non-empty files in a torrent,
+// and the pieces that cannot be attributed to a single one of them.
+struct Layout {
+    descriptors: Vec<Descriptor>,
+    spans: Vec<Span>,
+}
+
+// Torrent level metadata, used to report on and write resume data for a torrent.
+struct TorrentInfo {
+    name: String,
+    // SHA-1 of the info dictionary, zero for pure v2 torrents
+    info_hash: [u8; 20],
+    // SHA-256 of the info dictionary of v2 and hybrid torrents
+    info_hash_v2: Option<[u8; 32]>,
+    piece_length: i64,
+    piece_count: usize,
+    // Size of the torrent's v1 content, including pad files
+    total_size: i64,
+    // All files in torrent order, including empty and pad files
+    files: Vec<FileEntry>,
+}
+
+struct FileEntry {
+    path: PathBuf,
+    length: i64,
+    pad: bool,
+    // Path in the torrent, if the file was renamed to be safe for the output filesystem
+    renamed_from: Option<String>,
+}
+
+impl Layout {
+    // Moves the files of another torrent behind the ones of this layout.
+    fn append(&mut self, other: Layout) {
+        let base = self.descriptors.len();
+        self.descriptors.extend(other.descriptors);
+        self.spans.extend(other.spans.into_iter().map(|mut span| {
+            for part in &mut span.parts {
+                part.file += base;
+            }
+            span
+        }));
+    }
+}
+
 impl Descriptor {
     // Verify the content of a file against the extent hashes in the descriptor.
+    // Returns the index of the first piece that doesn't match, if any.
     // `threshold` is the fraction of correct hashes.
     // For example, if `threshold` is 0.5, the first half must match.
-    fn verify_file<T>(&self, file: &mut T, threshold: f32) -> IOResult<bool>
-    where
-        T: Seek + Read,
-    {
-        debug_assert!(threshold >= 0.0 && threshold <= 1.0);
-        let count = (self.extents.len() as f32 * threshold) as usize;
-        for i in 0..count {
-            let extent = &self.extents[i];
-            // Seek to block
-            file.seek(SeekFrom::Start(extent.offset as u64))?;
-            // Hash single block
-            let mut state = Sha1::new();
-            let bytes_hashed = std::io::copy(&mut file.take(extent.size as u64), &mut state)?;
-            if bytes_hashed as i64 != extent.size {
-                return Ok(false);
-            }
+    // Piece hashes are looked up in and added to `cache` if given.
+    fn verify_file(
+        &self,
+        file: &mut File,
+        threshold: f32,
+        cache: Option<(&Cache, &Path)>,
+    ) -> IOResult<Option<usize>> {
+        let meta = match cache {
+            Some(_) => Some(file.metadata()?),
+            None => None,
+        };
+        for extent in self.checked_extents(threshold) {
+            let key = PieceKey::new(extent);
+            let cached = match (cache, &meta) {
+                (Some((cache, _)), Some(meta)) => cache.get(meta, &key),
+                _ => None,
+            };
+            let digest = match cached {
+                Some(digest) => Some(digest),
+                None => {
+                    // Seek to block
+                    file.seek(SeekFrom::Start(extent.offset as u64))?;
+                    // Hash single block
+                    let padding = std::io::repeat(0).take(extent.padding as u64);
+                    let block = (&mut *file).take(extent.size as u64).chain(padding);
+                    let digest = extent.hash.digest(block, extent.size + extent.padding)?;
+                    if let (Some((cache, path)), Some(meta), Some(digest)) = (cache, &meta, &digest)
+                    {
+                        cache.insert(path, meta, key, digest.clone())?;
+                    }
+                    digest
+                }
+            };
             // Compare hashes
-            let hash = state.result();
-            if hash.as_slice() != &extent.hash[..] {
-                return Ok(false);
+            if digest.as_deref() != Some(extent.hash.as_bytes()) {
+                return Ok(Some(extent.piece));
             }
         }
-        Ok(true)
+        Ok(None)
+    }
+
+    // Extents checked by `verify_file` for the given `threshold`.
+    fn checked_extents(&self, threshold: f32) -> &[Extent] {
+        debug_assert!((0.0..=1.0).contains(&threshold));
+        let count = (self.extents.len() as f32 * threshold) as usize;
+        &self.extents[..count]
     }
 }
 
@@ -92,175 +315,947 @@ struct Match {
 }
 
 impl Match {
-    fn link(&self, symlink: bool) -> IOResult<()> {
+    // Whether the file is already at its place, from an incremental run.
+    fn in_place(&self) -> bool {
+        self.is_path == self.want_path
+    }
+
+    // Creates the file at `want_path`, returns the method used.
+    fn link(&self, method: Method, opts: &link::Options) -> IOResult<Method> {
         if let Some(parent) = self.want_path.parent() {
             create_dir_all(parent)?;
         }
-        if symlink {
-            soft_link(&self.is_path, &self.want_path)
-        } else {
-            hard_link(&self.is_path, &self.want_path)
+        link::create(&self.is_path, &self.want_path, method, opts)
+    }
+
+    // Creates the file from where a source matching several files was moved to,
+    // as a hard link, or a copy where hard links fail.
+    fn link_moved(&self, opts: &link::Options) -> IOResult<Method> {
+        match self.link(Method::Hardlink, opts) {
+            Err(e) if e.kind() != ErrorKind::AlreadyExists => self.link(Method::Copy, opts),
+            result => result,
+        }
+    }
+
+    // Applies the conflict policy if anything exists at `want_path`.
+    // Returns whether the file still has to be created,
+    // and where the existing file was renamed to. Dry runs only check.
+    fn resolve(
+        &self,
+        policy: Conflict,
+        ctx: &SearchContext,
+        descriptor: &Descriptor,
+        dry_run: bool,
+    ) -> Result<(bool, Option<PathBuf>), String> {
+        let want = &self.want_path;
+        if std::fs::symlink_metadata(want).is_err() {
+            return Ok((true, None));
+        }
+        let error = |e: std::io::Error| format!("{}: {}", want.display(), e);
+        match policy {
+            Conflict::Fail => Err(format!("{} already exists", want.display())),
+            Conflict::Skip if self.is_present(ctx, descriptor).map_err(error)? => Ok((false, None)),
+            Conflict::Skip => Err(format!(
+                "{} already exists and does not match the torrent",
+                want.display()
+            )),
+            _ if dry_run => Ok((true, None)),
+            Conflict::Replace => std::fs::remove_file(want)
+                .map(|_| (true, None))
+                .map_err(error),
+            Conflict::Rename => link::rename_aside(want)
+                .map(|backup| (true, Some(backup)))
+                .map_err(error),
+        }
+    }
+
+    // Whether the file at `want_path` already is the source, or has the same content.
+    fn is_present(&self, ctx: &SearchContext, descriptor: &Descriptor) -> IOResult<bool> {
+        let (want, is) = (
+            std::fs::metadata(&self.want_path)?,
+            std::fs::metadata(&self.is_path)?,
+        );
+        // Links from a previous run need no hashing
+        if link::inode(&want).is_some() && link::inode(&want) == link::inode(&is) {
+            return Ok(true);
+        }
+        if want.len() != descriptor.size as u64 {
+            return Ok(false);
+        }
+        // Files without pieces of their own are compared to the verified source
+        if descriptor.extents.is_empty() {
+            return link::same_content(&self.want_path, &self.is_path);
+        }
+        let mut file = File::open(&self.want_path)?;
+        let cache = ctx
+            .cache
+            .as_ref()
+            .map(|cache| (cache, self.want_path.as_path()));
+        Ok(descriptor
+            .verify_file(&mut file, ctx.hash_threshold, cache)?
+            .is_none())
+    }
+}
+
+// Directories that have to be created for the links, parents first.
+fn missing_dirs<'a, I: Iterator<Item = &'a Match>>(matches: I) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for m in matches {
+        let mut missing: Vec<&Path> = m
+            .want_path
+            .ancestors()
+            .skip(1)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .take_while(|dir| !dir.exists())
+            .collect();
+        missing.reverse();
+        for dir in missing {
+            if !dirs.iter().any(|d: &PathBuf| d == dir) {
+                dirs.push(dir.to_path_buf());
+            }
         }
     }
+    dirs
 }
 
 fn run(cli: clap::ArgMatches) -> Result<(), Box<dyn Error>> {
-    // Read torrent file and create hash descriptors
+    // Read torrent files and merge their hash descriptors
     let output_path = cli.value_of("output").unwrap();
     let output_path = PathBuf::from(output_path);
-    let torrent_path = cli.value_of("TORRENT").unwrap();
-    let descriptors = make_descriptors(torrent_path, &output_path)
-        .map_err(|e| format!("Failed to read torrent: {}", e))?;
+    let torrent_paths = find_torrents(cli.values_of("TORRENT").unwrap())?;
+    let mut report = Report::new(Format::from_name(cli.value_of("format").unwrap()));
+    let naming = naming(&cli)?;
+    let conflict = Conflict::from_name(cli.value_of("on_conflict").unwrap());
+    // Replaced files are gone, undoing the record couldn't bring them back
+    if conflict == Conflict::Replace && cli.is_present("record") {
+        return Err("--record can't undo --on-conflict replace, use rename instead".into());
+    }
+    let mut layout = Layout {
+        descriptors: vec![],
+        spans: vec![],
+    };
+    let mut torrents = Vec::new();
+    let mut failed = false;
+    for torrent_path in &torrent_paths {
+        // In batch mode, each torrent is linked into its own directory
+        let root = match torrent_path.file_stem() {
+            Some(stem) if torrent_paths.len() > 1 => output_path.join(stem),
+            _ => output_path.clone(),
+        };
+        match make_descriptors(torrent_path, &root, &naming) {
+            Ok((torrent_layout, info)) => {
+                let files = layout.descriptors.len();
+                let spans = layout.spans.len();
+                layout.append(torrent_layout);
+                torrents.push(LoadedTorrent {
+                    path: torrent_path.clone(),
+                    root,
+                    info,
+                    files: files..layout.descriptors.len(),
+                    spans: spans..layout.spans.len(),
+                });
+            }
+            Err(e) => {
+                let message = format!("Failed to read torrent {}: {}", torrent_path.display(), e);
+                report.error(Some(torrent_path), message);
+                failed = true;
+            }
+        }
+    }
+    if let Some(path) = cli.value_of("name_map") {
+        names::write_map(Path::new(path), &torrents)
+            .map_err(|e| format!("Failed to write name map: {}", e))?;
+    }
+
+    let hash_threshold = cli.value_of("hash").unwrap().parse::<f32>()?;
+    let cache = match cli.value_of("cache") {
+        Some(path) => {
+            Some(Cache::open(Path::new(path)).map_err(|e| format!("Failed to open cache: {}", e))?)
+        }
+        None => None,
+    };
+
+    // Incremental runs keep the files that are already in the output
+    let (intact, present) = if cli.is_present("incremental") {
+        existing::verify(
+            &layout.descriptors,
+            &layout.spans,
+            hash_threshold,
+            cache.as_ref(),
+        )
+    } else {
+        let none = vec![false; layout.descriptors.len()];
+        (none.clone(), none)
+    };
 
     // Lookup descriptors by size
-    let by_size: MultiMap<i64, Descriptor> =
-        descriptors.iter().map(|d| (d.size, d.clone())).collect();
+    let by_size: MultiMap<i64, usize> = layout
+        .descriptors
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| !present[i])
+        .map(|(i, d)| (d.size, i))
+        .collect();
 
     // Walk input directories and detect matching file sizes
-    let ctx = Rc::new(SearchContext {
+    let ctx = Arc::new(SearchContext {
+        descriptors: layout.descriptors,
         by_size,
         follow_symlinks: cli.is_present("follow_symlinks"),
-        create_symlinks: cli.is_present("create_symlinks"),
-        hash_threshold: cli.value_of("hash").unwrap().parse::<f32>()?,
+        link_method: link_method(&cli),
+        hash_threshold,
+        cache,
+        output: link::canonicalize_existing(&output_path)?,
+    });
+    let jobs = match cli.value_of("jobs") {
+        Some(jobs) => jobs.parse::<usize>()?.max(1),
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let mut device_jobs = HashMap::new();
+    for arg in cli.values_of("device_jobs").into_iter().flatten() {
+        let (path, n) = match arg.rfind('=') {
+            Some(i) => (&arg[..i], arg[i + 1..].parse::<usize>()?.max(1)),
+            None => return Err(format!("Invalid device jobs {}, expected PATH=N", arg).into()),
+        };
+        let meta = std::fs::metadata(path).map_err(|e| format!("{}: {}", path, e))?;
+        device_jobs.insert(search::device(&meta), n);
+    }
+    let input_dirs: Vec<&str> = cli.values_of("input").unwrap().collect();
+    // Files in place are their preferred source,
+    // those sharing unverified pieces are still searched for
+    let mut candidates: Vec<Vec<PathBuf>> = ctx
+        .descriptors
+        .iter()
+        .zip(&intact)
+        .map(|(d, &intact)| {
+            if intact {
+                vec![d.path.clone()]
+            } else {
+                Vec::new()
+            }
+        })
+        .collect();
+    let mut rejected = vec![Vec::new(); ctx.descriptors.len()];
+    for (c, rejection) in search::search(&ctx, &input_dirs, jobs, &device_jobs) {
+        match rejection {
+            None => candidates[c.file].push(c.path),
+            Some(reason) => rejected[c.file].push((c.path, reason)),
+        }
+    }
+
+    let dry_run = cli.is_present("dry_run");
+    let copy_fallback = cli.is_present("copy_fallback");
+    let symlink_target = if cli.is_present("relative_symlinks") {
+        SymlinkTarget::Relative
+    } else if cli.is_present("absolute_symlinks") {
+        SymlinkTarget::Absolute
+    } else {
+        SymlinkTarget::AsFound
+    };
+    let write_fastresume = cli.is_present("fastresume") && !dry_run;
+    let write_transmission = cli.is_present("transmission") && !dry_run;
+    let transmission_torrents = cli
+        .value_of("transmission_torrents")
+        .map(Path::new)
+        .filter(|_| !dry_run);
+    // Transmission keeps resume files in a directory beside its torrents directory
+    let transmission_resume = cli.value_of("transmission_torrents").map(|dir| {
+        let config = Path::new(dir).parent().unwrap_or_else(|| Path::new(""));
+        config.join("resume")
     });
-    let input_dirs = cli.values_of_lossy("input").unwrap();
-    for input_dir in input_dirs {
-        for m in search_dir(&input_dir, &ctx) {
+
+    // Check pieces spanning multiple files and pick a source for each file
+    let max_combinations = cli.value_of("max_combinations").unwrap().parse::<usize>()?;
+    let (sources, verified_spans) = solver::cross_verify(
+        &ctx.descriptors,
+        &layout.spans,
+        &candidates,
+        max_combinations,
+    );
+    let matches: Vec<(usize, Match)> = ctx
+        .descriptors
+        .iter()
+        .zip(&sources)
+        .enumerate()
+        .filter_map(|(i, (descriptor, source))| {
+            let m = Match {
+                is_path: source.clone()?,
+                want_path: descriptor.path.clone(),
+            };
+            Some((i, m))
+        })
+        .collect();
+    let dirs = missing_dirs(matches.iter().map(|(_, m)| m).filter(|m| !m.in_place()));
+    if dry_run && report.is_text() {
+        for dir in &dirs {
+            println!("mkdir {}", dir.to_string_lossy());
+        }
+    }
+    let planned: Vec<&Match> = matches
+        .iter()
+        .map(|(_, m)| m)
+        .filter(|m| !m.in_place())
+        .collect();
+    if let Some(path) = cli.value_of("manifest") {
+        manifest::write_manifest(Path::new(path), &planned, ctx.link_method, symlink_target)
+            .map_err(|e| format!("Failed to write manifest: {}", e))?;
+    }
+    if let Some(path) = cli.value_of("script") {
+        manifest::write_script(Path::new(path), &planned, ctx.link_method, symlink_target)
+            .map_err(|e| format!("Failed to write script: {}", e))?;
+    }
+    // The record is written as links are created, so it must be writable before the first
+    let mut record = match (cli.value_of("record"), dry_run) {
+        (Some(path), false) => Some(
+            manifest::Record::create(Path::new(path))
+                .map_err(|e| format!("Failed to write record: {}", e))?,
+        ),
+        _ => None,
+    };
+    let record_error = |e: std::io::Error| format!("Failed to write record: {}", e);
+    if let Some(record) = &mut record {
+        for dir in &dirs {
+            record.dir(dir).map_err(record_error)?;
+        }
+    }
+    let mut link_errors = vec![None; ctx.descriptors.len()];
+    let mut used_methods = vec![None; ctx.descriptors.len()];
+    // Sources matching several files are moved once, to the first of them
+    let mut moved: HashMap<&Path, &Path> = HashMap::new();
+    for (i, m) in &matches {
+        // Dry runs only report what would fail
+        let descriptor = &ctx.descriptors[*i];
+        let resolved = if m.in_place() {
+            Ok((false, None))
+        } else {
+            m.resolve(conflict, &ctx, descriptor, dry_run)
+        };
+        // Existing files renamed aside, with the path they had
+        if let (Ok((_, Some(backup))), Some(record)) = (&resolved, &mut record) {
+            record.backup(&m.want_path, backup).map_err(record_error)?;
+        }
+        let result = match resolved.map(|(create, _)| create) {
+            Err(e) => Err(e),
+            Ok(false) => Ok(None),
+            Ok(true) if dry_run => Ok(Some(ctx.link_method)),
+            Ok(true) => {
+                // Copies are checked against all pieces of the file,
+                // files without pieces of their own against the file they were copied from
+                let first = moved.get(m.is_path.as_path()).copied();
+                let source = first.unwrap_or(&m.is_path);
+                let verify = |path: &Path| -> IOResult<bool> {
+                    if descriptor.extents.is_empty() {
+                        return link::same_content(path, source);
+                    }
+                    let mut file = File::open(path)?;
+                    Ok(descriptor.verify_file(&mut file, 1.0, None)?.is_none())
+                };
+                let opts = link::Options {
+                    symlink_target,
+                    verify: Some(&verify),
+                };
+                match first {
+                    // The others are linked from where the source went
+                    Some(first) => Match {
+                        is_path: first.to_path_buf(),
+                        want_path: m.want_path.clone(),
+                    }
+                    .link_moved(&opts),
+                    None => match m.link(ctx.link_method, &opts) {
+                        Err(e) if copy_fallback && link::is_cross_device(&e) => {
+                            m.link(Method::Copy, &opts)
+                        }
+                        result => result,
+                    },
+                }
+                .map(Some)
+                .map_err(|e| e.to_string())
+            }
+        };
+        if report.is_text() {
+            let want = m.want_path.to_string_lossy();
+            let is = m.is_path.to_string_lossy();
+            // Automatic mode names the method it settled on
+            match result {
+                Ok(Some(method)) if method != ctx.link_method => {
+                    println!("{} <= {} ({})", want, is, method.name())
+                }
+                Ok(None) => println!("{} is already present", want),
+                _ => println!("{} <= {}", want, is),
+            }
+        }
+        if let (Ok(Some(method)), Some(record)) = (&result, &mut record) {
+            record.link(m, *method).map_err(record_error)?;
+        }
+        if let Ok(Some(Method::Move)) = result {
+            moved.insert(&m.is_path, &m.want_path);
+        }
+        match result {
+            Ok(method) => used_methods[*i] = method,
+            Err(e) => {
+                eprintln!("{}", e);
+                link_errors[*i] = Some(e);
+                failed = true;
+            }
+        }
+    }
+    // Files whose link failed are not in place, their pieces must not be trusted
+    let placed: Vec<Option<PathBuf>> = sources
+        .iter()
+        .zip(&link_errors)
+        .map(|(source, error)| source.clone().filter(|_| error.is_none()))
+        .collect();
+    let unplaced: HashSet<&Path> = ctx
+        .descriptors
+        .iter()
+        .zip(&placed)
+        .filter(|(_, source)| source.is_none())
+        .map(|(d, _)| d.path.as_path())
+        .collect();
+
+    // Report completeness of each torrent
+    let mut summary = Summary::default();
+    for torrent in &torrents {
+        let descriptors = &ctx.descriptors[torrent.files.clone()];
+        let torrent_sources = &placed[torrent.files.clone()];
+        let found = torrent_sources.iter().filter(|s| s.is_some()).count();
+        let total_size: i64 = descriptors.iter().map(|d| d.size).sum();
+        let found_size: i64 = descriptors
+            .iter()
+            .zip(torrent_sources)
+            .filter(|(_, s)| s.is_some())
+            .map(|(d, _)| d.size)
+            .sum();
+        let completion = Completion::new(
+            torrent,
+            &ctx.descriptors,
+            &layout.spans,
+            &placed,
+            &link_errors,
+            &verified_spans,
+            ctx.hash_threshold,
+        );
+        let (verified, verified_size) = completion.count(PieceState::Verified);
+        let (implied, implied_size) = completion.count(PieceState::Implied);
+        let (missing, missing_size) = completion.count(PieceState::Missing);
+        let percent = if total_size > 0 {
+            (verified_size + implied_size) as f64 * 100.0 / total_size as f64
+        } else {
+            100.0
+        };
+        if report.is_text() {
             println!(
-                "{} <= {}",
-                m.want_path.to_string_lossy(),
-                m.is_path.to_string_lossy()
+                "{}: {}/{} files, {}/{} bytes",
+                torrent.path.to_string_lossy(),
+                found,
+                descriptors.len(),
+                found_size,
+                total_size
             );
-            if let Err(e) = m.link(ctx.create_symlinks) {
-                eprintln!("{}", e);
+            println!(
+                "  {} verified, {} implied, {} missing of {} pieces, {:.1}% of bytes present",
+                verified,
+                implied,
+                missing,
+                completion.pieces.len(),
+                percent
+            );
+            for (descriptor, state) in descriptors.iter().zip(&completion.files) {
+                println!("  {:8} {}", state.name(), descriptor.path.to_string_lossy());
             }
         }
+        summary.files += descriptors.len();
+        summary.found += found;
+        summary.size += total_size;
+        summary.found_size += found_size;
+
+        // Write resume data for clients
+        let have = completion.verified();
+        let files_placed: Vec<bool> = torrent
+            .info
+            .files
+            .iter()
+            .map(|file| !unplaced.contains(file.path.as_path()))
+            .collect();
+        let mut results = Vec::new();
+        if write_fastresume {
+            results.push(resume::write_fastresume(
+                &torrent.info,
+                &torrent.root,
+                &have,
+                &files_placed,
+            ));
+        }
+        if write_transmission {
+            results.push(resume::write_transmission_resume(
+                &torrent.info,
+                &torrent.root,
+                transmission_resume.as_deref().unwrap_or(&torrent.root),
+                &have,
+                &files_placed,
+            ));
+        }
+        if let Some(dir) = transmission_torrents {
+            results.push(resume::copy_transmission_torrent(
+                &torrent.info,
+                &torrent.root,
+                &torrent.path,
+                dir,
+            ));
+        }
+        let mut resume_paths = Vec::new();
+        for result in results {
+            match result {
+                Ok(path) => {
+                    if report.is_text() {
+                        println!("Wrote {}", path.to_string_lossy());
+                    }
+                    resume_paths.push(path.to_string_lossy().into_owned());
+                }
+                Err(e) => {
+                    let message = format!(
+                        "Failed to write resume data for {}: {}",
+                        torrent.path.display(),
+                        e
+                    );
+                    report.error(Some(&torrent.path), message);
+                    failed = true;
+                }
+            }
+        }
+
+        if !report.is_text() {
+            let files: Vec<_> = torrent
+                .files
+                .clone()
+                .zip(&completion.files)
+                .map(|(i, state)| {
+                    // Candidates that verified on their own but were not chosen
+                    let unchosen = candidates[i]
+                        .iter()
+                        .filter(|&path| Some(path) != sources[i].as_ref())
+                        .map(|path| {
+                            let reason = if sources[i].is_some() {
+                                "Another candidate was chosen"
+                            } else {
+                                "Pieces shared with other files do not match"
+                            };
+                            (path, reason)
+                        });
+                    let rejected: Vec<_> = rejected[i]
+                        .iter()
+                        .map(|(path, reason)| (path, reason.as_str()))
+                        .chain(unchosen)
+                        .map(|(path, reason)| {
+                            json!({"path": path.to_string_lossy(), "reason": reason})
+                        })
+                        .collect();
+                    json!({
+                        "path": ctx.descriptors[i].path.to_string_lossy(),
+                        "size": ctx.descriptors[i].size,
+                        "status": state.name(),
+                        "source": sources[i].as_ref().map(|path| path.to_string_lossy()),
+                        "link": used_methods[i].map(Method::name),
+                        "error": link_errors[i],
+                        "rejected": rejected,
+                    })
+                })
+                .collect();
+            report.torrent(json!({
+                "torrent": torrent.path.to_string_lossy(),
+                "root": torrent.root.to_string_lossy(),
+                "files": files,
+                "found_files": found,
+                "found_bytes": found_size,
+                "total_bytes": total_size,
+                "pieces": {
+                    "total": completion.pieces.len(),
+                    "verified": verified,
+                    "implied": implied,
+                    "missing": missing,
+                },
+                "bytes": {
+                    "verified": verified_size,
+                    "implied": implied_size,
+                    "missing": missing_size,
+                },
+                "percent": percent,
+                "resume": resume_paths,
+            }));
+        }
     }
+    report.finish(json!({
+        "torrents": torrents.len(),
+        "failed": failed,
+        "files": summary.files,
+        "found_files": summary.found,
+        "total_bytes": summary.size,
+        "found_bytes": summary.found_size,
+        "dry_run": dry_run,
+        "dirs": dirs.iter().map(|dir| dir.to_string_lossy()).collect::<Vec<_>>(),
+    }));
 
+    if failed {
+        return Err("Some torrents could not be read, linked or resumed".into());
+    }
     Ok(())
 }
 
-struct SearchContext {
-    by_size: MultiMap<i64, Descriptor>,
-    follow_symlinks: bool,
-    create_symlinks: bool,
-    hash_threshold: f32,
+// Totals over all torrents of a run.
+#[derive(Default)]
+struct Summary {
+    files: usize,
+    found: usize,
+    size: i64,
+    found_size: i64,
 }
 
-// Searches a directory at path for files that match descriptors in `by_size`.
-// If `symlinks` is enabled, files behind symbolic links are also considered.
-fn search_dir(path: &str, ctx: &Rc<SearchContext>) -> impl Iterator<Item = Match> {
-    let hash_threshold = ctx.hash_threshold;
-    let ctx = Rc::clone(ctx);
-    WalkDir::new(path)
-        .follow_links(ctx.follow_symlinks)
-        .into_iter()
-        // Print and filter errors
-        .filter_map(|entry| entry.map_err(|err| eprintln!("{}", err)).ok())
-        // Ignore directories
-        .filter(|entry| entry.file_type().is_file())
-        // Get metadata
-        .filter_map(|entry| {
-            entry
-                .metadata()
-                .map_err(|err| eprintln!("{}", err))
-                .ok()
-                .map(|meta| (entry, meta))
-        })
-        // Lookup sizes to get matches
-        .filter_map(move |(entry, meta)| {
-            let size = meta.len();
-            ctx.by_size
-                .get(&(size as i64))
-                .map(|d| (entry.path().to_path_buf(), d.clone()))
-        })
-        // Verify hashes
-        .filter(move |(path, d)| {
-            File::open(path)
-                .and_then(|mut file| d.verify_file(&mut file, hash_threshold))
-                .unwrap_or_else(|err| {
-                    eprintln!("{}", err);
-                    false
-                })
-        })
-        // Map to match struct
-        .map(|(path, descriptor)| {
-            let want_path: &PathBuf = descriptor.path.borrow();
-            Match {
-                is_path: path,
-                want_path: want_path.clone(),
+// Torrent whose descriptors and spans are at `files` and `spans` in the merged layout.
+struct LoadedTorrent {
+    path: PathBuf,
+    // Directory the torrent's files are linked into
+    root: PathBuf,
+    info: TorrentInfo,
+    files: Range<usize>,
+    spans: Range<usize>,
+}
+
+// Expands directories to the .torrent files in them.
+fn find_torrents<'a, I>(args: I) -> IOResult<Vec<PathBuf>>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let mut paths = Vec::new();
+    for arg in args {
+        let path = PathBuf::from(arg);
+        if !path.is_dir() {
+            paths.push(path);
+            continue;
+        }
+        let mut torrents = Vec::new();
+        for entry in std::fs::read_dir(&path)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "torrent") {
+                torrents.push(path);
             }
-        })
+        }
+        torrents.sort();
+        paths.extend(torrents);
+    }
+    Ok(paths)
+}
+
+fn prune_cache(cli: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let cache = Cache::open(Path::new(cli.value_of("CACHE").unwrap()))
+        .map_err(|e| format!("Failed to open cache: {}", e))?;
+    let (kept, removed) = cache.prune()?;
+    println!("Kept {} files, removed {} stale files", kept, removed);
+    Ok(())
+}
+
+// Checks and translations of torrent paths selected on the command line.
+fn naming(cli: &clap::ArgMatches) -> Result<Naming, Box<dyn Error>> {
+    let policies: Vec<Policy> = cli
+        .values_of("translate_names")
+        .into_iter()
+        .flatten()
+        .filter_map(Policy::from_name)
+        .collect();
+    if policies.contains(&Policy::Nfc) && policies.contains(&Policy::Nfd) {
+        return Err("Names can't be normalized to both NFC and NFD".into());
+    }
+    Ok(Naming {
+        mode: sanitize::Mode::from_name(cli.value_of("sanitize").unwrap()),
+        policies,
+    })
+}
+
+// Method of creating output files selected on the command line.
+fn link_method(cli: &clap::ArgMatches) -> Method {
+    if cli.is_present("create_symlinks")
+        || cli.is_present("relative_symlinks")
+        || cli.is_present("absolute_symlinks")
+    {
+        Method::Symlink
+    } else if cli.is_present("reflink") {
+        Method::Reflink
+    } else if cli.is_present("auto_link") {
+        Method::Auto
+    } else if cli.is_present("copy") {
+        Method::Copy
+    } else if cli.is_present("move") {
+        Method::Move
+    } else {
+        Method::Hardlink
+    }
+}
+
+fn apply_manifest(cli: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let (created, skipped) = manifest::apply(Path::new(cli.value_of("MANIFEST").unwrap()))?;
+    println!("Created {} links, skipped {}", created, skipped);
+    if skipped > 0 {
+        return Err("Some links could not be created".into());
+    }
+    Ok(())
+}
+
+fn undo_record(cli: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let (removed, restored, skipped) = manifest::undo(Path::new(cli.value_of("RECORD").unwrap()))?;
+    println!(
+        "Undid {} links, restored {} files, skipped {}",
+        removed, restored, skipped
+    );
+    if skipped > 0 {
+        return Err("Some links could not be undone".into());
+    }
+    Ok(())
 }
 
 fn make_descriptors(
-    torrent_path: &str,
-    want_prefix: &PathBuf,
-) -> Result<Vec<Descriptor>, Box<dyn Error>> {
-    let torrent = Torrent::read_from_file(torrent_path)?;
+    torrent_path: &Path,
+    want_prefix: &Path,
+    naming: &Naming,
+) -> Result<(Layout, TorrentInfo), Box<dyn Error>> {
+    let bytes = std::fs::read(torrent_path)?;
+    let raw_info = raw_info(&bytes).ok_or("Torrent is malformed or has no info dictionary")?;
+    let elems = BencodeElem::from_bytes(&bytes)?;
+    let root = match elems.as_slice() {
+        [root] => Some(root),
+        _ => None,
+    };
+    // Prefer v2 metadata, it covers every file independently
+    if let Some(root) = root {
+        if let Some(torrent) = v2::make_descriptors(root, raw_info, want_prefix, naming)? {
+            return Ok(torrent);
+        }
+    }
+    let torrent = Torrent::read_from_bytes(&bytes)?;
+    let mut info_hash = [0u8; 20];
+    info_hash.copy_from_slice(Sha1::digest(raw_info).as_slice());
+    let mut info = TorrentInfo {
+        name: torrent.name.clone(),
+        info_hash,
+        info_hash_v2: None,
+        piece_length: torrent.piece_length,
+        piece_count: torrent.pieces.len(),
+        total_size: torrent.length,
+        files: Vec::new(),
+    };
     if let Some(ref files) = torrent.files {
         // Directory torrent
         if files.is_empty() || torrent.pieces.is_empty() {
-            return Ok(vec![]);
-        }
-        let dir_name = want_prefix.join(&torrent.name);
-        let mut descriptors = Vec::<Descriptor>::new();
-        let mut pieces = torrent.pieces.iter();
-        let mut file_offset = 0i64;
-        for file in files {
-            // If offset exceeds file, skip to next
-            if file_offset >= file.length {
-                file_offset -= file.length;
-                continue;
-            }
-            let mut extents = Vec::new();
-            // Iterate pieces until end of file reached
-            while file.length - file_offset >= torrent.piece_length {
-                let piece = unwrap_or_break!(pieces.next());
-                extents.push(Extent {
-                    offset: file_offset,
-                    size: torrent.piece_length,
-                    hash: unwrap_piece(&piece),
-                });
-                file_offset += torrent.piece_length;
-            }
-            // Finalize descriptor
-            if !extents.is_empty() {
+            let layout = Layout {
+                descriptors: vec![],
+                spans: vec![],
+            };
+            return Ok((layout, info));
+        }
+        let paths = root
+            .and_then(raw_paths)
+            .filter(|paths| paths.len() == files.len())
+            .ok_or(r#""files" has no valid paths"#)?;
+        let mut descriptors = Vec::new();
+        // Offset, size and descriptor of files within the torrent.
+        // Pad files take up space but are never searched for.
+        let mut ranges: Vec<(i64, i64, Option<usize>)> = Vec::new();
+        let mut total = 0i64;
+        let mut names = naming.translator();
+        for (file, path) in files.iter().zip(paths) {
+            let pad = is_pad_file(file);
+            let mut components = vec![torrent.name.clone()];
+            components.extend(path);
+            let (path, renamed_from) = names.file(&components)?;
+            let path = want_prefix.join(path);
+            // Empty files have no content to search for
+            let descriptor = if pad || file.length == 0 {
+                None
+            } else {
                 descriptors.push(Descriptor {
-                    path: dir_name.join(file.path.clone()),
-                    extents,
+                    path: path.clone(),
                     size: file.length,
+                    extents: Vec::new(),
                 });
+                Some(descriptors.len() - 1)
+            };
+            info.files.push(FileEntry {
+                path,
+                length: file.length,
+                pad,
+                renamed_from,
+            });
+            if file.length > 0 {
+                ranges.push((total, file.length, descriptor));
+                total += file.length;
+            }
+        }
+        let mut spans = Vec::new();
+        let mut first = 0usize;
+        for (index, piece) in torrent.pieces.iter().enumerate() {
+            let piece_start = index as i64 * torrent.piece_length;
+            if piece_start >= total {
+                break;
+            }
+            let piece_end = (piece_start + torrent.piece_length).min(total);
+            // Skip files that end before this piece
+            while ranges[first].0 + ranges[first].1 <= piece_start {
+                first += 1;
+            }
+            // Collect slices of all files overlapping the piece,
+            // padding counts towards the file before it
+            let mut parts: Vec<Part> = Vec::new();
+            let mut leading_padding = false;
+            for &(start, size, descriptor) in &ranges[first..] {
+                if start >= piece_end {
+                    break;
+                }
+                let offset = piece_start.max(start) - start;
+                let end = piece_end.min(start + size) - start;
+                match (descriptor, parts.last_mut()) {
+                    (Some(file), _) => parts.push(Part {
+                        file,
+                        offset,
+                        size: end - offset,
+                        padding: 0,
+                    }),
+                    (None, Some(last)) => last.padding += end - offset,
+                    (None, None) => leading_padding = true,
+                }
+            }
+            // Pad files only ever follow regular files
+            if leading_padding || parts.is_empty() {
+                continue;
             }
-            // Ignore piece that overlaps two files
-            if file.length - file_offset > 0 {
-                file_offset = torrent.piece_length - (file.length - file_offset);
-                unwrap_or_break!(pieces.next());
+            if parts.len() > 1 {
+                spans.push(Span {
+                    piece: index,
+                    hash: unwrap_piece(piece),
+                    parts,
+                });
+            } else {
+                // Piece within a single file, the final piece may be short
+                descriptors[parts[0].file].extents.push(Extent {
+                    piece: index,
+                    offset: parts[0].offset,
+                    size: parts[0].size,
+                    padding: parts[0].padding,
+                    hash: PieceHash::Sha1(unwrap_piece(piece)),
+                });
             }
         }
-        Ok(descriptors)
+        Ok((Layout { descriptors, spans }, info))
     } else {
         // Single file torrent, collect all pieces and return single descriptor.
         let extents = torrent
             .pieces
             .iter()
-            .scan(0i64, |offset, piece| {
+            .enumerate()
+            .scan(0i64, |offset, (index, piece)| {
+                // The final piece ends with the file
                 let ext = Extent {
+                    piece: index,
                     offset: *offset,
-                    size: torrent.piece_length,
-                    hash: unwrap_piece(piece),
+                    size: torrent.piece_length.min(torrent.length - *offset),
+                    padding: 0,
+                    hash: PieceHash::Sha1(unwrap_piece(piece)),
                 };
                 *offset += torrent.piece_length;
                 Some(ext)
             })
             .collect();
-        let mut path = want_prefix.clone();
-        path.push(&torrent.name);
-        Ok(vec![Descriptor {
-            path,
-            size: torrent.length,
-            extents,
-        }])
+        let (path, renamed_from) = naming.translator().file(&[&torrent.name])?;
+        let path = want_prefix.join(path);
+        info.files.push(FileEntry {
+            path: path.clone(),
+            length: torrent.length,
+            pad: false,
+            renamed_from,
+        });
+        let layout = Layout {
+            descriptors: vec![Descriptor {
+                path,
+                size: torrent.length,
+                extents,
+            }],
+            spans: vec![],
+        };
+        Ok((layout, info))
+    }
+}
+
+// Returns the encoded info dictionary of a torrent, which info hashes are computed from.
+fn raw_info(bytes: &[u8]) -> Option<&[u8]> {
+    // Returns the end of the bencoded value at `pos`, if it is within `bytes`.
+    // Malformed torrents may nest deeply or claim huge lengths.
+    fn skip(bytes: &[u8], pos: usize, depth: usize) -> Option<usize> {
+        let end = match *bytes.get(pos)? {
+            b'i' => pos + bytes[pos..].iter().position(|&b| b == b'e')? + 1,
+            b'l' | b'd' if depth < 256 => {
+                let mut pos = pos + 1;
+                while *bytes.get(pos)? != b'e' {
+                    pos = skip(bytes, pos, depth + 1)?;
+                }
+                pos + 1
+            }
+            b'0'..=b'9' => {
+                let colon = pos + bytes[pos..].iter().position(|&b| b == b':')?;
+                let len: usize = std::str::from_utf8(&bytes[pos..colon]).ok()?.parse().ok()?;
+                colon.checked_add(1)?.checked_add(len)?
+            }
+            _ => return None,
+        };
+        Some(end).filter(|&end| end <= bytes.len())
+    }
+    if bytes.first() != Some(&b'd') {
+        return None;
+    }
+    let mut pos = 1;
+    while *bytes.get(pos)? != b'e' {
+        let key_end = skip(bytes, pos, 0)?;
+        let value_end = skip(bytes, key_end, 0)?;
+        if &bytes[pos..key_end] == b"4:info" {
+            return bytes.get(key_end..value_end);
+        }
+        pos = value_end;
+    }
+    None
+}
+
+// Path components of each file of a multi file torrent, as they are in the info dictionary.
+// lava_torrent joins them into paths, which drops empty and absolute components.
+fn raw_paths(root: &BencodeElem) -> Option<Vec<Vec<String>>> {
+    let component = |elem: &BencodeElem| match elem {
+        BencodeElem::String(name) => Some(name.clone()),
+        BencodeElem::Bytes(name) => Some(String::from_utf8_lossy(name).into_owned()),
+        _ => None,
+    };
+    let files = match root {
+        BencodeElem::Dictionary(root) => match root.get("info")? {
+            BencodeElem::Dictionary(info) => info.get("files")?,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    match files {
+        BencodeElem::List(files) => files
+            .iter()
+            .map(|file| match file {
+                BencodeElem::Dictionary(file) => match file.get("path")? {
+                    BencodeElem::List(path) => path.iter().map(component).collect(),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+// Pad files (BEP 47) fill the gap between files to align them to pieces.
+// Their content is all zeros.
+fn is_pad_file(file: &lava_torrent::torrent::v1::File) -> bool {
+    match file.extra_fields.as_ref().and_then(|f| f.get("attr")) {
+        Some(BencodeElem::String(attr)) => attr.contains('p'),
+        _ => false,
     }
 }
 
@@ -270,13 +1265,3 @@ fn unwrap_piece(piece: &[u8]) -> [u8; 20] {
     array.copy_from_slice(bytes);
     array
 }
-
-#[cfg(target_family = "windows")]
-pub fn soft_link<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> IOResult<()> {
-    std::os::windows::fs::symlink_file(src, dst)
-}
-
-#[cfg(target_family = "unix")]
-pub fn soft_link<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> IOResult<()> {
-    std::os::unix::fs::symlink(src, dst)
-}
@@ -0,0 +1,415 @@
+// Link plans saved to be applied later, possibly on another machine.
+// Manifests record the size and mtime of each source,
+// so links are only created if the source didn't change since it was verified.
+// Records of created directories and links allow undoing a run.
+
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fs::{self, File, Metadata};
+use std::io::{BufWriter, Result as IOResult, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde_json::{json, Value};
+
+use super::link::{self, inode, Method, SymlinkTarget};
+use super::Match;
+
+// Writes the links as a JSON manifest for the apply subcommand.
+pub fn write_manifest(
+    path: &Path,
+    matches: &[&Match],
+    method: Method,
+    symlink_target: SymlinkTarget,
+) -> Result<(), Box<dyn Error>> {
+    let working_dir = std::env::current_dir()?;
+    let mut links = Vec::new();
+    for m in matches {
+        let meta =
+            fs::metadata(&m.is_path).map_err(|e| format!("{}: {}", m.is_path.display(), e))?;
+        let (mtime, mtime_nsec) = mtime(&meta);
+        links.push(json!({
+            "source": working_dir.join(&m.is_path).to_string_lossy(),
+            "target": working_dir.join(&m.want_path).to_string_lossy(),
+            "size": meta.len(),
+            "mtime": mtime,
+            "mtime_nsec": mtime_nsec,
+        }));
+    }
+    let manifest = json!({
+        "version": 1,
+        "method": method.name(),
+        "symlink_target": symlink_target.name(),
+        "links": links,
+    });
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut writer, &manifest)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Writes the links as a POSIX shell script of mkdir and ln commands.
+pub fn write_script(
+    path: &Path,
+    matches: &[&Match],
+    method: Method,
+    symlink_target: SymlinkTarget,
+) -> Result<(), Box<dyn Error>> {
+    let working_dir = std::env::current_dir()?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "#!/bin/sh")?;
+    writeln!(writer, "set -e")?;
+    let dirs: BTreeSet<PathBuf> = matches
+        .iter()
+        .filter_map(|m| Some(working_dir.join(m.want_path.parent()?)))
+        .collect();
+    for dir in dirs {
+        writeln!(writer, "mkdir -p -- {}", quote(&dir))?;
+    }
+    let command = |method: Method| match method {
+        Method::Hardlink => "ln --",
+        Method::Symlink => "ln -s --",
+        Method::Reflink => "cp --reflink=always --",
+        Method::Copy => "cp --",
+        Method::Move => "mv --",
+        Method::Auto => unreachable!(),
+    };
+    let methods: &[Method] = match method {
+        Method::Auto => &[
+            Method::Reflink,
+            Method::Hardlink,
+            Method::Symlink,
+            Method::Copy,
+        ],
+        _ => std::slice::from_ref(&method),
+    };
+    // Sources matching several files are moved once, to the first of them
+    let mut moved: HashMap<&Path, PathBuf> = HashMap::new();
+    for m in matches {
+        let is = working_dir.join(&m.is_path);
+        let want = working_dir.join(&m.want_path);
+        if let Some(first) = moved.get(m.is_path.as_path()) {
+            writeln!(
+                writer,
+                "ln -- {0} {1} 2>/dev/null || cp -- {0} {1}",
+                quote(first),
+                quote(&want)
+            )?;
+            continue;
+        }
+        if method == Method::Move {
+            moved.insert(&m.is_path, want.clone());
+        }
+        let commands = methods
+            .iter()
+            .map(|&method| {
+                // Symbolic links contain their target as planned, not as the shell resolves it
+                let source = match method {
+                    Method::Symlink => symlink_target
+                        .resolve(&is, &want)
+                        .map_err(|e| format!("{}: {}", is.display(), e))?,
+                    _ => is.clone(),
+                };
+                Ok(format!(
+                    "{} {} {}",
+                    command(method),
+                    quote(&source),
+                    quote(&want)
+                ))
+            })
+            .collect::<Result<Vec<String>, String>>()?;
+        // Errors of methods that may be followed by another are expected
+        writeln!(writer, "{}", commands.join(" 2>/dev/null || "))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Creates the links of a manifest whose sources still have the recorded size and mtime.
+// Returns the number of links created and skipped.
+pub fn apply(path: &Path) -> Result<(usize, usize), Box<dyn Error>> {
+    let manifest: Value = serde_json::from_reader(File::open(path)?)
+        .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+    if manifest["version"] != 1 {
+        return Err(format!("{} is not a version 1 manifest", path.display()).into());
+    }
+    let method = manifest["method"]
+        .as_str()
+        .and_then(Method::from_name)
+        .ok_or_else(|| format!("{} has no valid link method", path.display()))?;
+    // Manifests written before symbolic link targets were configurable use them as found
+    let symlink_target = match manifest["symlink_target"].as_str() {
+        Some(name) => SymlinkTarget::from_name(name)
+            .ok_or_else(|| format!("{} has no valid symlink target", path.display()))?,
+        None => SymlinkTarget::AsFound,
+    };
+    let opts = link::Options {
+        symlink_target,
+        verify: None,
+    };
+    let links = manifest["links"]
+        .as_array()
+        .ok_or_else(|| format!("{} has no links", path.display()))?;
+    let (mut created, mut skipped) = (0, 0);
+    // Sources matching several files are moved once, to the first of them
+    let mut moved: HashMap<PathBuf, PathBuf> = HashMap::new();
+    for link in links {
+        let (m, size, recorded) = match parse_link(link) {
+            Some(link) => link,
+            None => return Err(format!("Invalid link in {}: {}", path.display(), link).into()),
+        };
+        let first = moved.get(&m.is_path).cloned();
+        let result = match (first, fs::metadata(&m.is_path)) {
+            (Some(first), _) => Match {
+                is_path: first,
+                want_path: m.want_path.clone(),
+            }
+            .link_moved(&opts)
+            .map_err(|e| format!("{}: {}", m.want_path.display(), e)),
+            (None, Ok(meta)) if meta.len() != size || mtime(&meta) != recorded => Err(format!(
+                "{} changed since it was verified",
+                m.is_path.display()
+            )),
+            (None, Ok(_)) => m
+                .link(method, &opts)
+                .map_err(|e| format!("{}: {}", m.want_path.display(), e)),
+            (None, Err(e)) => Err(format!("{}: {}", m.is_path.display(), e)),
+        };
+        if let (Ok(Method::Move), false) = (&result, moved.contains_key(&m.is_path)) {
+            moved.insert(m.is_path.clone(), m.want_path.clone());
+        }
+        match result {
+            Ok(_) => {
+                println!(
+                    "{} <= {}",
+                    m.want_path.to_string_lossy(),
+                    m.is_path.to_string_lossy()
+                );
+                created += 1;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                skipped += 1;
+            }
+        }
+    }
+    Ok((created, skipped))
+}
+
+// Directories and links created by a run, for the undo subcommand,
+// along with the method each link was created with,
+// and the files that were renamed aside for them.
+// Entries are written as JSON lines as the run goes,
+// so a run that fails or is killed partway can still be undone.
+pub struct Record {
+    file: File,
+    working_dir: PathBuf,
+}
+
+impl Record {
+    // Creates the record at `path`, replacing any existing one.
+    pub fn create(path: &Path) -> Result<Record, Box<dyn Error>> {
+        let mut record = Record {
+            file: File::create(path)?,
+            working_dir: std::env::current_dir()?,
+        };
+        record.append(json!({ "version": 2 }))?;
+        Ok(record)
+    }
+
+    // Directories are recorded before they are created, undo only removes them if empty.
+    pub fn dir(&mut self, dir: &Path) -> IOResult<()> {
+        let dir = self.working_dir.join(dir);
+        self.append(json!({ "dir": dir.to_string_lossy() }))
+    }
+
+    // Symbolic links are recorded with their destination, other files with their inode.
+    pub fn link(&mut self, m: &Match, method: Method) -> IOResult<()> {
+        let symlink = method == Method::Symlink;
+        let inode = fs::symlink_metadata(&m.want_path)
+            .ok()
+            .and_then(|meta| inode(&meta));
+        // Symbolic links are compared by what they point to, as written
+        let points_to = if symlink {
+            fs::read_link(&m.want_path).ok()
+        } else {
+            None
+        };
+        let link = json!({
+            "source": self.working_dir.join(&m.is_path).to_string_lossy(),
+            "target": self.working_dir.join(&m.want_path).to_string_lossy(),
+            "method": method.name(),
+            "points_to": points_to.map(|path| path.to_string_lossy().into_owned()),
+            "inode": inode,
+        });
+        self.append(json!({ "link": link }))
+    }
+
+    pub fn backup(&mut self, target: &Path, backup: &Path) -> IOResult<()> {
+        let backup = json!({
+            "target": self.working_dir.join(target).to_string_lossy(),
+            "backup": self.working_dir.join(backup).to_string_lossy(),
+        });
+        self.append(json!({ "backup": backup }))
+    }
+
+    // Each entry is a single write, so a killed run leaves at most the last line cut short.
+    fn append(&mut self, entry: Value) -> IOResult<()> {
+        let mut line = entry.to_string();
+        line.push('\n');
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+// Reads a record as a version 1 record,
+// which was a single JSON document written at the end of a run.
+fn read_record(path: &Path) -> Result<Value, Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    let mut lines = text.lines().peekable();
+    let version = lines
+        .next()
+        .and_then(|line| serde_json::from_str::<Value>(line).ok());
+    if version.is_none_or(|v| v["version"] != 2) {
+        return serde_json::from_str(&text)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e).into());
+    }
+    let (mut links, mut dirs, mut backups) = (Vec::new(), Vec::new(), Vec::new());
+    while let Some(line) = lines.next() {
+        let mut entry = match serde_json::from_str::<Value>(line) {
+            Ok(entry) => entry,
+            // A run killed while writing cuts the last line short
+            Err(_) if lines.peek().is_none() && !text.ends_with('\n') => break,
+            Err(e) => return Err(format!("Failed to parse {}: {}", path.display(), e).into()),
+        };
+        if entry.get("link").is_some() {
+            links.push(entry["link"].take());
+        } else if entry.get("dir").is_some() {
+            dirs.push(entry["dir"].take());
+        } else if entry.get("backup").is_some() {
+            backups.push(entry["backup"].take());
+        } else {
+            return Err(format!("Invalid entry in {}: {}", path.display(), line).into());
+        }
+    }
+    Ok(json!({
+        "version": 1,
+        "links": links,
+        "dirs": dirs,
+        "backups": backups,
+    }))
+}
+
+// Removes the links of a record that are still the recorded files,
+// moves renamed files back where the links have gone,
+// then removes the recorded directories that are empty.
+// Returns the number of links undone, files restored and changes skipped.
+pub fn undo(path: &Path) -> Result<(usize, usize, usize), Box<dyn Error>> {
+    let record = read_record(path)?;
+    let (links, dirs) = match (record["links"].as_array(), record["dirs"].as_array()) {
+        (Some(links), Some(dirs)) if record["version"] == 1 => (links, dirs),
+        _ => return Err(format!("{} is not a version 1 or 2 record", path.display()).into()),
+    };
+    let (mut removed, mut skipped) = (0, 0);
+    for link in links {
+        let target = match link["target"].as_str() {
+            Some(target) => Path::new(target),
+            None => return Err(format!("Invalid link in {}: {}", path.display(), link).into()),
+        };
+        // Moved files are moved back, everything else is removed
+        let source = link["source"].as_str().map(Path::new);
+        let result = check_link(link, target).and_then(|_| match source {
+            Some(source) if link["method"] == Method::Move.name() => {
+                link::create(target, source, Method::Move, &Default::default())
+                    .map(|_| format!("Moved {} back to {}", target.display(), source.display()))
+                    .map_err(|e| format!("{}: {}", source.display(), e))
+            }
+            _ => fs::remove_file(target)
+                .map(|_| format!("Removed {}", target.display()))
+                .map_err(|e| format!("{}: {}", target.display(), e)),
+        });
+        match result {
+            Ok(message) => {
+                println!("{}", message);
+                removed += 1;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                skipped += 1;
+            }
+        }
+    }
+    // Records of older versions have no backups
+    let mut restored = 0;
+    for backup in record["backups"].as_array().into_iter().flatten() {
+        let (target, backup) = match (backup["target"].as_str(), backup["backup"].as_str()) {
+            (Some(target), Some(backup)) => (Path::new(target), Path::new(backup)),
+            _ => return Err(format!("Invalid backup in {}: {}", path.display(), backup).into()),
+        };
+        let result = if fs::symlink_metadata(target).is_ok() {
+            Err(format!("{} still exists", target.display()))
+        } else {
+            fs::rename(backup, target).map_err(|e| format!("{}: {}", backup.display(), e))
+        };
+        match result {
+            Ok(()) => {
+                println!("Restored {} from {}", target.display(), backup.display());
+                restored += 1;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                skipped += 1;
+            }
+        }
+    }
+    // Children were created after their parents, and only empty directories can be removed
+    for dir in dirs.iter().rev().filter_map(|dir| dir.as_str()) {
+        if fs::remove_dir(dir).is_ok() {
+            println!("Removed {}", dir);
+        }
+    }
+    Ok((removed, restored, skipped))
+}
+
+// Checks that a recorded link still points to its source, or still is the recorded inode.
+fn check_link(link: &Value, target: &Path) -> Result<(), String> {
+    let meta = fs::symlink_metadata(target).map_err(|e| format!("{}: {}", target.display(), e))?;
+    let unchanged = if link["method"] == Method::Symlink.name() {
+        let points_to = link["points_to"].as_str().map(Path::new);
+        meta.file_type().is_symlink() && fs::read_link(target).ok().as_deref() == points_to
+    } else {
+        let recorded = link["inode"]
+            .as_array()
+            .and_then(|a| Some((a.first()?.as_u64()?, a.get(1)?.as_u64()?)));
+        !meta.file_type().is_symlink() && recorded.is_some() && inode(&meta) == recorded
+    };
+    if !unchanged {
+        return Err(format!(
+            "{} was replaced since it was linked",
+            target.display()
+        ));
+    }
+    Ok(())
+}
+
+fn parse_link(link: &Value) -> Option<(Match, u64, (i64, i64))> {
+    let m = Match {
+        is_path: PathBuf::from(link["source"].as_str()?),
+        want_path: PathBuf::from(link["target"].as_str()?),
+    };
+    let mtime = (link["mtime"].as_i64()?, link["mtime_nsec"].as_i64()?);
+    Some((m, link["size"].as_u64()?, mtime))
+}
+
+// Modification time as seconds and nanoseconds since the epoch.
+fn mtime(meta: &Metadata) -> (i64, i64) {
+    meta.modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or((0, 0), |d| (d.as_secs() as i64, d.subsec_nanos() as i64))
+}
+
+// Quotes a path for the shell, single quotes are the only character needing care.
+fn quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
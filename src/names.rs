@@ -0,0 +1,319 @@
+// Translation of torrent paths to names the output filesystem accepts.
+// Renamed files are recorded in a mapping file, so clients that can rename files
+// of a torrent can be pointed at the translated layout.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use unicode_normalization::UnicodeNormalization;
+
+use super::resume::torrent_id;
+use super::sanitize::{self, Mode};
+use super::LoadedTorrent;
+
+// Longest name most filesystems accept, in bytes.
+const MAX_NAME: usize = 255;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    // Shorten long names, keeping the extension and adding a hash of the full name
+    Truncate,
+    // Replace characters that exFAT, NTFS and SMB shares don't accept
+    Replace,
+    // Unicode normalization forms, composed as on most systems or decomposed as on macOS
+    Nfc,
+    Nfd,
+    // Rename files whose paths only differ in case
+    Case,
+}
+
+impl Policy {
+    pub fn from_name(name: &str) -> Option<Policy> {
+        match name {
+            "truncate" => Some(Policy::Truncate),
+            "replace" => Some(Policy::Replace),
+            "nfc" => Some(Policy::Nfc),
+            "nfd" => Some(Policy::Nfd),
+            "case" => Some(Policy::Case),
+            _ => None,
+        }
+    }
+}
+
+// How paths of torrents are checked and translated.
+#[derive(Clone)]
+pub struct Naming {
+    pub mode: Mode,
+    pub policies: Vec<Policy>,
+}
+
+impl Naming {
+    // Starts translating the paths of a torrent.
+    pub fn translator(&self) -> Translator<'_> {
+        Translator {
+            naming: self,
+            taken: HashSet::new(),
+            dirs: HashMap::new(),
+        }
+    }
+
+    fn has(&self, policy: Policy) -> bool {
+        self.policies.contains(&policy)
+    }
+}
+
+pub struct Translator<'a> {
+    naming: &'a Naming,
+    // Lowercase paths of the files translated so far
+    taken: HashSet<String>,
+    // Translated directories by their lowercase path
+    dirs: HashMap<String, String>,
+}
+
+impl Translator<'_> {
+    // Translates the path of a file from its components in the torrent,
+    // including the torrent's name for multi file torrents.
+    // Returns the path relative to the torrent's directory,
+    // and the path in the torrent if they differ.
+    pub fn file<S: AsRef<str>>(
+        &mut self,
+        components: &[S],
+    ) -> Result<(PathBuf, Option<String>), String> {
+        let original = components
+            .iter()
+            .map(|c| c.as_ref())
+            .collect::<Vec<_>>()
+            .join("/");
+        let safe = sanitize::path(components.iter().map(|c| c.as_ref()), self.naming.mode)?;
+        let mut names: Vec<String> = safe
+            .iter()
+            .map(|name| self.name(&name.to_string_lossy()))
+            .collect();
+        if self.naming.has(Policy::Case) {
+            self.dedupe(&mut names);
+        }
+        let path: PathBuf = names.iter().collect();
+        let renamed = if names.join("/") == original {
+            None
+        } else {
+            Some(original)
+        };
+        Ok((path, renamed))
+    }
+
+    // Applies normalization, replacement and truncation, in that order,
+    // since the former ones change the length of the name.
+    fn name(&self, name: &str) -> String {
+        let mut name = if self.naming.has(Policy::Nfc) {
+            name.nfc().collect()
+        } else if self.naming.has(Policy::Nfd) {
+            name.nfd().collect()
+        } else {
+            name.to_string()
+        };
+        if self.naming.has(Policy::Replace) {
+            name = replace_illegal(&name);
+        }
+        if self.naming.has(Policy::Truncate) {
+            name = truncate(&name, "");
+        }
+        name
+    }
+
+    // Adds a counter to names until their path is unique regardless of case.
+    // Directories that only differ in case are merged, like a case-insensitive filesystem would,
+    // taking the case of the first of them.
+    fn dedupe(&mut self, names: &mut [String]) {
+        let last = names.len() - 1;
+        for depth in 0..last {
+            let key = names[..=depth].join("/").to_lowercase();
+            if let Some(dir) = self.dirs.get(&key) {
+                names[depth] = dir.clone();
+                continue;
+            }
+            // A new directory must not take the path of a file
+            let name = names[depth].clone();
+            for n in 1.. {
+                names[depth] = self.numbered(&name, n);
+                let path = names[..=depth].join("/").to_lowercase();
+                if !self.taken.contains(&path) {
+                    self.dirs.insert(path, names[depth].clone());
+                    break;
+                }
+            }
+            self.dirs.insert(key, names[depth].clone());
+        }
+        let name = names[last].clone();
+        for n in 1.. {
+            names[last] = self.numbered(&name, n);
+            let path = names.join("/").to_lowercase();
+            if !self.dirs.contains_key(&path) && self.taken.insert(path) {
+                break;
+            }
+        }
+    }
+
+    // Name with a counter before its extension, the first one is unchanged.
+    fn numbered(&self, name: &str, n: usize) -> String {
+        if n == 1 {
+            return name.to_string();
+        }
+        let (stem, ext) = split_extension(name);
+        let suffix = format!("~{}{}", n, ext);
+        if self.naming.has(Policy::Truncate) {
+            truncate(stem, &suffix)
+        } else {
+            format!("{}{}", stem, suffix)
+        }
+    }
+}
+
+// Characters that are illegal on FAT, exFAT and NTFS, and trailing dots and spaces.
+fn replace_illegal(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let kept = name.trim_end_matches(['.', ' ']).len();
+    let trailing = name.len() - kept;
+    name.truncate(kept);
+    name.extend(std::iter::repeat_n('_', trailing));
+    name
+}
+
+// Shortens `name` followed by `suffix` to the maximum name length.
+// Long names keep their extension and get a hash of the full name, so they stay unique.
+fn truncate(name: &str, suffix: &str) -> String {
+    if name.len() + suffix.len() <= MAX_NAME {
+        return format!("{}{}", name, suffix);
+    }
+    let hash: String = Sha1::digest(name.as_bytes())[..4]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let (stem, ext) = match split_extension(name) {
+        // Extensions that long are more likely part of the name
+        (stem, ext) if ext.len() <= 16 && suffix.is_empty() => (stem, ext),
+        _ => (name, ""),
+    };
+    let tail = format!("~{}{}{}", hash, suffix, ext);
+    let mut end = MAX_NAME.saturating_sub(tail.len()).min(stem.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], tail)
+}
+
+// Splits a file name before its extension, hidden files have none.
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    }
+}
+
+// Writes the renamed files of each torrent as JSON,
+// with their index in the torrent, the path in the torrent and the translated path.
+pub fn write_map(path: &Path, torrents: &[LoadedTorrent]) -> Result<(), Box<dyn Error>> {
+    let mut records = Vec::new();
+    for torrent in torrents {
+        let renamed: Vec<_> = torrent
+            .info
+            .files
+            .iter()
+            .enumerate()
+            .filter_map(|(index, file)| {
+                let original = file.renamed_from.as_ref()?;
+                let translated = file.path.strip_prefix(&torrent.root).ok()?;
+                Some(json!({
+                    "index": index,
+                    "original": original,
+                    "renamed": translated.to_string_lossy(),
+                }))
+            })
+            .collect();
+        if renamed.is_empty() {
+            continue;
+        }
+        records.push(json!({
+            "torrent": torrent.path.to_string_lossy(),
+            "info_hash": torrent_id(&torrent.info),
+            "root": torrent.root.to_string_lossy(),
+            "files": renamed,
+        }));
+    }
+    let map = json!({
+        "version": 1,
+        "torrents": records,
+    });
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut writer, &map)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{Naming, Policy};
+    use crate::sanitize::Mode;
+
+    fn translate(paths: &[&[&str]]) -> Vec<PathBuf> {
+        let naming = Naming {
+            mode: Mode::Strict,
+            policies: vec![Policy::Case],
+        };
+        let mut translator = naming.translator();
+        paths
+            .iter()
+            .map(|components| translator.file(components).unwrap().0)
+            .collect()
+    }
+
+    #[test]
+    fn renames_files_differing_in_case() {
+        assert_eq!(
+            translate(&[&["t", "a.txt"], &["t", "A.txt"], &["t", "a.TXT"]]),
+            vec![
+                PathBuf::from("t/a.txt"),
+                PathBuf::from("t/A~2.txt"),
+                PathBuf::from("t/a~3.TXT"),
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_directories_differing_in_case() {
+        assert_eq!(
+            translate(&[&["t", "Dir", "x"], &["t", "dir", "y"], &["t", "DIR", "x"]]),
+            vec![
+                PathBuf::from("t/Dir/x"),
+                PathBuf::from("t/Dir/y"),
+                PathBuf::from("t/Dir/x~2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn separates_files_and_directories() {
+        assert_eq!(
+            translate(&[&["t", "dir"], &["t", "Dir", "x"], &["t", "DIR"]]),
+            vec![
+                PathBuf::from("t/dir"),
+                PathBuf::from("t/Dir~2/x"),
+                PathBuf::from("t/DIR~3"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,156 @@
+// Accounting of which pieces of a torrent are covered by the chosen sources.
+
+use std::path::PathBuf;
+
+use super::{Descriptor, Extent, LoadedTorrent, Span};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PieceState {
+    // Not all files of the piece were matched, or the piece failed to verify
+    Missing,
+    // The files of the piece were matched, but the piece was skipped by the hash threshold
+    Implied,
+    // The piece was hashed and matched
+    Verified,
+}
+
+// Ordered from worst to best, so a file is only as complete as its worst piece.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FileState {
+    // The file was not matched
+    Missing,
+    // The file was matched, but could not be placed in the output
+    Failed,
+    // The file was matched, but some of its pieces are missing
+    Partial,
+    Implied,
+    Verified,
+}
+
+impl FileState {
+    pub fn name(self) -> &'static str {
+        match self {
+            FileState::Missing => "missing",
+            FileState::Failed => "failed",
+            FileState::Partial => "partial",
+            FileState::Implied => "implied",
+            FileState::Verified => "verified",
+        }
+    }
+}
+
+pub struct Completion {
+    pub pieces: Vec<PieceState>,
+    // Content bytes of each piece, excluding padding
+    pub sizes: Vec<i64>,
+    // State of each file of the torrent, in layout order
+    pub files: Vec<FileState>,
+}
+
+impl Completion {
+    pub fn new(
+        torrent: &LoadedTorrent,
+        descriptors: &[Descriptor],
+        spans: &[Span],
+        sources: &[Option<PathBuf>],
+        link_errors: &[Option<String>],
+        verified_spans: &[bool],
+        threshold: f32,
+    ) -> Completion {
+        let mut pieces = vec![PieceState::Missing; torrent.info.piece_count];
+        let mut sizes = vec![0; torrent.info.piece_count];
+        let mut files = vec![FileState::Verified; torrent.files.len()];
+        let piece_length = torrent.info.piece_length;
+        for i in torrent.files.clone() {
+            let d = &descriptors[i];
+            let checked = d.checked_extents(threshold).len();
+            for (n, extent) in d.extents.iter().enumerate() {
+                let state = match sources[i] {
+                    None => PieceState::Missing,
+                    Some(_) if n < checked => PieceState::Verified,
+                    Some(_) => PieceState::Implied,
+                };
+                for (piece, size) in covered(extent, piece_length) {
+                    sizes[piece] = size;
+                    pieces[piece] = state;
+                }
+            }
+        }
+        // Files of a verified span may still be unmatched if another of their spans failed
+        for s in torrent.spans.clone() {
+            let span = &spans[s];
+            let linked = span.parts.iter().all(|p| sources[p.file].is_some());
+            let state = if verified_spans[s] && linked {
+                PieceState::Verified
+            } else {
+                PieceState::Missing
+            };
+            pieces[span.piece] = state;
+            sizes[span.piece] = span.parts.iter().map(|p| p.size).sum();
+        }
+        // Files are only as complete as their worst piece
+        let mut worst = |file: usize, piece: usize| {
+            let state = match pieces[piece] {
+                PieceState::Missing => FileState::Partial,
+                PieceState::Implied => FileState::Implied,
+                PieceState::Verified => FileState::Verified,
+            };
+            let file = &mut files[file - torrent.files.start];
+            *file = (*file).min(state);
+        };
+        for i in torrent.files.clone() {
+            for extent in &descriptors[i].extents {
+                for (piece, _) in covered(extent, piece_length) {
+                    worst(i, piece);
+                }
+            }
+        }
+        for s in torrent.spans.clone() {
+            for part in &spans[s].parts {
+                worst(part.file, spans[s].piece);
+            }
+        }
+        for i in torrent.files.clone() {
+            if link_errors[i].is_some() {
+                files[i - torrent.files.start] = FileState::Failed;
+            } else if sources[i].is_none() {
+                files[i - torrent.files.start] = FileState::Missing;
+            }
+        }
+        Completion {
+            pieces,
+            sizes,
+            files,
+        }
+    }
+
+    // Pieces that were verified, clients may trust them without hashing.
+    pub fn verified(&self) -> Vec<bool> {
+        self.pieces
+            .iter()
+            .map(|&state| state == PieceState::Verified)
+            .collect()
+    }
+
+    // Returns the number of pieces in a state and their content bytes.
+    pub fn count(&self, state: PieceState) -> (usize, i64) {
+        self.pieces
+            .iter()
+            .zip(&self.sizes)
+            .filter(|(&s, _)| s == state)
+            .fold((0, 0), |(count, bytes), (_, &size)| {
+                (count + 1, bytes + size)
+            })
+    }
+}
+
+// Pieces covered by an extent and their content bytes.
+// Files of v2 torrents without a piece layer have one extent over all their pieces.
+fn covered(extent: &Extent, piece_length: i64) -> impl Iterator<Item = (usize, i64)> {
+    let count = (extent.size as u64).div_ceil(piece_length as u64).max(1) as usize;
+    let (first, size) = (extent.piece, extent.size);
+    (0..count).map(move |n| {
+        let offset = n as i64 * piece_length;
+        (first + n, piece_length.min(size - offset))
+    })
+}
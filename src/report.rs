@@ -0,0 +1,98 @@
+// Results of a run as JSON records, so tools don't have to parse the text output.
+
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    // One document printed at the end
+    Json,
+    // One record per line, printed as soon as it is known
+    Ndjson,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Format {
+        match name {
+            "json" => Format::Json,
+            "ndjson" => Format::Ndjson,
+            _ => Format::Text,
+        }
+    }
+}
+
+pub struct Report {
+    pub format: Format,
+    torrents: Vec<Value>,
+    errors: Vec<Value>,
+}
+
+impl Report {
+    pub fn new(format: Format) -> Report {
+        Report {
+            format,
+            torrents: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.format == Format::Text
+    }
+
+    // Prints an error and records it, along with the torrent it belongs to.
+    pub fn error(&mut self, torrent: Option<&Path>, message: String) {
+        eprintln!("{}", message);
+        let record = json!({
+            "torrent": torrent.map(|path| path.to_string_lossy()),
+            "message": message,
+        });
+        match self.format {
+            Format::Text => {}
+            Format::Json => self.errors.push(record),
+            Format::Ndjson => print_record("error", record),
+        }
+    }
+
+    // Records a torrent, its files are in the "files" array of `record`.
+    // NDJSON prints each file on its own line, followed by the torrent without them.
+    pub fn torrent(&mut self, mut record: Value) {
+        match self.format {
+            Format::Text => {}
+            Format::Json => self.torrents.push(record),
+            Format::Ndjson => {
+                let torrent = record["torrent"].clone();
+                if let Some(Value::Array(files)) = record.as_object_mut().unwrap().remove("files") {
+                    for mut file in files {
+                        file["torrent"] = torrent.clone();
+                        print_record("file", file);
+                    }
+                }
+                print_record("torrent", record);
+            }
+        }
+    }
+
+    // Prints the summary, and with JSON the whole document.
+    pub fn finish(self, summary: Value) {
+        match self.format {
+            Format::Text => {}
+            Format::Json => println!(
+                "{}",
+                json!({
+                    "torrents": self.torrents,
+                    "errors": self.errors,
+                    "summary": summary,
+                })
+            ),
+            Format::Ndjson => print_record("summary", summary),
+        }
+    }
+}
+
+fn print_record(kind: &str, mut record: Value) {
+    record["type"] = json!(kind);
+    println!("{}", record);
+}
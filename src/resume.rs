@@ -0,0 +1,365 @@
+// Resume data for BitTorrent clients, so linked torrents can be seeded without a recheck.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lava_torrent::bencode::BencodeElem;
+
+use super::sanitize;
+use super::{FileEntry, TorrentInfo};
+
+// Transmission tracks completion in blocks of this size.
+const TRANSMISSION_BLOCK_SIZE: i64 = 16384;
+
+// Writes a libtorrent fastresume file for the torrent linked into `root`,
+// named after the torrent's info hash as qBittorrent expects.
+// `have` marks the verified pieces, which the client then trusts without hashing.
+// `placed` marks the files that were linked, in torrent order.
+// Renamed files are mapped to their translated paths.
+pub fn write_fastresume(
+    info: &TorrentInfo,
+    root: &Path,
+    have: &[bool],
+    placed: &[bool],
+) -> Result<PathBuf, Box<dyn Error>> {
+    fs::create_dir_all(root)?;
+    let save_path = fs::canonicalize(root)?;
+    let now = unix_time(SystemTime::now());
+    let complete = have.iter().all(|&h| h);
+
+    // Sizes and mtimes of the linked files, clients compare them to detect changes
+    let file_sizes = info
+        .files
+        .iter()
+        .zip(placed)
+        .map(|(file, &placed)| {
+            let (size, mtime) = match linked_mtime(file).filter(|_| placed) {
+                Some(mtime) => (file.length, mtime),
+                None => (0, 0),
+            };
+            BencodeElem::List(vec![
+                BencodeElem::Integer(size),
+                BencodeElem::Integer(mtime),
+            ])
+        })
+        .collect();
+
+    let mut dict = HashMap::new();
+    let mut set = |key: &str, value: BencodeElem| {
+        dict.insert(key.to_string(), value);
+    };
+    set(
+        "file-format",
+        BencodeElem::String("libtorrent resume file".into()),
+    );
+    set("file-version", BencodeElem::Integer(1));
+    set("info-hash", BencodeElem::Bytes(info.info_hash.to_vec()));
+    if let Some(hash) = info.info_hash_v2 {
+        set("info-hash2", BencodeElem::Bytes(hash.to_vec()));
+    }
+    set("name", BencodeElem::String(info.name.clone()));
+    set(
+        "save_path",
+        BencodeElem::String(save_path.to_string_lossy().into_owned()),
+    );
+    set(
+        "pieces",
+        BencodeElem::Bytes(have.iter().map(|&h| h as u8).collect()),
+    );
+    set("file_sizes", BencodeElem::List(file_sizes));
+    // Files renamed for the output filesystem, relative to the save path
+    if info.files.iter().any(|file| file.renamed_from.is_some()) {
+        let mapped_files = info
+            .files
+            .iter()
+            .map(|file| {
+                let mapped = match (&file.renamed_from, file.path.strip_prefix(root)) {
+                    (Some(_), Ok(path)) => path.to_string_lossy().into_owned(),
+                    _ => String::new(),
+                };
+                BencodeElem::String(mapped)
+            })
+            .collect();
+        set("mapped_files", BencodeElem::List(mapped_files));
+    }
+    set("allocation", BencodeElem::String("sparse".into()));
+    set("paused", BencodeElem::Integer(0));
+    set("auto_managed", BencodeElem::Integer(1));
+    set("added_time", BencodeElem::Integer(now));
+    set(
+        "completed_time",
+        BencodeElem::Integer(if complete { now } else { 0 }),
+    );
+
+    let path = root.join(format!("{}.fastresume", torrent_id(info)));
+    fs::write(&path, BencodeElem::Dictionary(dict).encode())?;
+    Ok(path)
+}
+
+// Writes a Transmission resume file into `dir` for the torrent linked into `root`.
+// Pieces are stored as a bitfield of 16 KiB blocks,
+// and files are marked as checked at their mtime so Transmission doesn't recheck them.
+// Renamed files are listed with their translated paths.
+pub fn write_transmission_resume(
+    info: &TorrentInfo,
+    root: &Path,
+    dir: &Path,
+    have: &[bool],
+    placed: &[bool],
+) -> Result<PathBuf, Box<dyn Error>> {
+    let name = output_name(info, root)?;
+    let file_name = transmission_name(info, root)?;
+    fs::create_dir_all(root)?;
+    let destination = fs::canonicalize(root)?;
+    let now = unix_time(SystemTime::now());
+    let complete = have.iter().all(|&h| h);
+
+    let blocks = transmission_blocks(have, info.piece_length, info.total_size);
+
+    let mtimes: Vec<i64> = info
+        .files
+        .iter()
+        .zip(placed)
+        .map(|(file, &placed)| linked_mtime(file).filter(|_| placed).unwrap_or(0))
+        .collect();
+    let mut progress = HashMap::new();
+    progress.insert("blocks".to_string(), blocks);
+    if complete {
+        progress.insert("have".to_string(), BencodeElem::String("all".into()));
+    }
+    progress.insert(
+        "mtimes".to_string(),
+        BencodeElem::List(mtimes.iter().map(|&t| BencodeElem::Integer(t)).collect()),
+    );
+    progress.insert(
+        "time-checked".to_string(),
+        BencodeElem::List(mtimes.iter().map(|&t| BencodeElem::Integer(t)).collect()),
+    );
+
+    let mut dict = HashMap::new();
+    let mut set = |key: &str, value: BencodeElem| {
+        dict.insert(key.to_string(), value);
+    };
+    set(
+        "destination",
+        BencodeElem::String(destination.to_string_lossy().into_owned()),
+    );
+    set("name", BencodeElem::String(name));
+    set("progress", BencodeElem::Dictionary(progress));
+    // Paths of all files relative to the destination, when any was renamed
+    if info.files.iter().any(|file| file.renamed_from.is_some()) {
+        let files = info
+            .files
+            .iter()
+            .map(|file| {
+                let path = file.path.strip_prefix(root).unwrap_or(&file.path);
+                BencodeElem::String(path.to_string_lossy().into_owned())
+            })
+            .collect();
+        set("files", BencodeElem::List(files));
+    }
+    set("paused", BencodeElem::Integer(0));
+    set("added-date", BencodeElem::Integer(now));
+    set(
+        "done-date",
+        BencodeElem::Integer(if complete { now } else { 0 }),
+    );
+
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.resume", file_name));
+    fs::write(&path, BencodeElem::Dictionary(dict).encode())?;
+    Ok(path)
+}
+
+// Bitfield of the completed 16 KiB blocks of a torrent, or "all" or "none".
+// A block is complete if all pieces overlapping it are.
+fn transmission_blocks(have: &[bool], piece_length: i64, total_size: i64) -> BencodeElem {
+    if have.iter().all(|&h| h) {
+        return BencodeElem::String("all".into());
+    }
+    let block_count = (total_size + TRANSMISSION_BLOCK_SIZE - 1) / TRANSMISSION_BLOCK_SIZE;
+    let mut blocks = vec![0u8; ((block_count + 7) / 8) as usize];
+    for block in 0..block_count {
+        let start = block * TRANSMISSION_BLOCK_SIZE;
+        let end = (start + TRANSMISSION_BLOCK_SIZE).min(total_size);
+        let first = (start / piece_length) as usize;
+        let last = ((end - 1) / piece_length) as usize;
+        if have[first..=last].iter().all(|&h| h) {
+            blocks[(block / 8) as usize] |= 0x80 >> (block % 8);
+        }
+    }
+    if blocks.iter().all(|&b| b == 0) {
+        BencodeElem::String("none".into())
+    } else {
+        BencodeElem::Bytes(blocks)
+    }
+}
+
+// Copies the torrent file into the torrents directory of a Transmission config,
+// named to match its resume file for the torrent linked into `root`.
+pub fn copy_transmission_torrent(
+    info: &TorrentInfo,
+    root: &Path,
+    torrent_path: &Path,
+    torrents_dir: &Path,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let name = transmission_name(info, root)?;
+    fs::create_dir_all(torrents_dir)?;
+    let path = torrents_dir.join(format!("{}.torrent", name));
+    fs::copy(torrent_path, &path)?;
+    Ok(path)
+}
+
+// Transmission names its files after the torrent name and the start of the info hash.
+// Newer versions migrate these names to the full info hash on startup.
+fn transmission_name(info: &TorrentInfo, root: &Path) -> Result<String, Box<dyn Error>> {
+    if info.info_hash == [0u8; 20] {
+        return Err("Transmission doesn't support v2 only torrents".into());
+    }
+    Ok(format!(
+        "{}.{}",
+        output_name(info, root)?,
+        &torrent_id(info)[..16]
+    ))
+}
+
+// Name of the torrent as linked into `root`, after sanitizing and translation.
+// The name in the torrent is untrusted and must not end up in paths.
+fn output_name(info: &TorrentInfo, root: &Path) -> Result<String, Box<dyn Error>> {
+    let name = info
+        .files
+        .first()
+        .and_then(|file| file.path.strip_prefix(root).ok()?.iter().next())
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or("Torrent has no files")?;
+    if let Some(reason) = sanitize::check(&name) {
+        return Err(format!("Unsafe torrent name {:?}: {}", name, reason).into());
+    }
+    Ok(name)
+}
+
+// Returns the mtime of a file if it was linked with the expected size.
+fn linked_mtime(file: &FileEntry) -> Option<i64> {
+    match fs::metadata(&file.path) {
+        Ok(meta) if !file.pad && meta.len() == file.length as u64 => {
+            Some(meta.modified().map_or(0, unix_time))
+        }
+        _ => None,
+    }
+}
+
+// Hex info hash identifying the torrent in clients.
+// Pure v2 torrents are identified by their truncated v2 info hash.
+pub fn torrent_id(info: &TorrentInfo) -> String {
+    let hash = match info.info_hash_v2 {
+        Some(ref v2) if info.info_hash == [0u8; 20] => &v2[..20],
+        _ => &info.info_hash[..],
+    };
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use lava_torrent::bencode::BencodeElem;
+
+    use super::{transmission_blocks, write_fastresume};
+    use crate::{FileEntry, TorrentInfo};
+
+    fn bitfield(have: &[bool], piece_length: i64, total_size: i64) -> Vec<u8> {
+        match transmission_blocks(have, piece_length, total_size) {
+            BencodeElem::Bytes(blocks) => blocks,
+            other => panic!("expected a bitfield, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn blocks_all_or_none() {
+        assert_eq!(
+            transmission_blocks(&[true, true], 32768, 50000),
+            BencodeElem::String("all".into())
+        );
+        assert_eq!(
+            transmission_blocks(&[false, false], 32768, 50000),
+            BencodeElem::String("none".into())
+        );
+    }
+
+    #[test]
+    fn blocks_of_pieces_of_several_blocks() {
+        // Four blocks, the last one short, from two pieces
+        assert_eq!(bitfield(&[false, true], 32768, 50000), vec![0b0011_0000]);
+        // Bits past the first byte
+        let have = [true, false, false, false, false, true];
+        assert_eq!(bitfield(&have, 32768, 6 * 32768), vec![0xc0, 0x30]);
+    }
+
+    #[test]
+    fn blocks_of_pieces_smaller_than_a_block() {
+        // A block is complete only if both of its pieces are
+        let have = [true, true, true, false, false, true];
+        assert_eq!(bitfield(&have, 8192, 6 * 8192), vec![0b1000_0000]);
+    }
+
+    #[test]
+    fn blocks_of_pieces_not_aligned_to_blocks() {
+        // Pieces of 1.5 blocks: the second block overlaps both pieces
+        let have = [true, false, true];
+        assert_eq!(bitfield(&have, 24576, 3 * 24576), vec![0b1001_1000]);
+        let have = [true, true, false];
+        assert_eq!(bitfield(&have, 24576, 3 * 24576), vec![0b1110_0000]);
+    }
+
+    #[test]
+    fn fastresume_has_a_byte_per_piece() {
+        let root =
+            std::env::temp_dir().join(format!("find-torrent-data-resume-{}", std::process::id()));
+        let file = |name: &str, length: i64, pad: bool| FileEntry {
+            path: root.join(name),
+            length,
+            pad,
+            renamed_from: None,
+        };
+        let info = TorrentInfo {
+            name: "t".into(),
+            info_hash: [7; 20],
+            info_hash_v2: None,
+            piece_length: 16384,
+            piece_count: 3,
+            total_size: 40000,
+            files: vec![
+                file("a", 10000, false),
+                file(".pad/6384", 6384, true),
+                file("b", 23616, false),
+            ],
+        };
+        let path: PathBuf =
+            write_fastresume(&info, &root, &[true, false, true], &[true; 3]).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        let dict = match &BencodeElem::from_bytes(&bytes).unwrap()[0] {
+            BencodeElem::Dictionary(dict) => dict.clone(),
+            other => panic!("expected a dictionary, got {:?}", other),
+        };
+        let pieces = match &dict["pieces"] {
+            BencodeElem::Bytes(bytes) => bytes.clone(),
+            BencodeElem::String(string) => string.clone().into_bytes(),
+            other => panic!("expected bytes, got {:?}", other),
+        };
+        assert_eq!(pieces, vec![1, 0, 1]);
+        match &dict["file_sizes"] {
+            BencodeElem::List(sizes) => assert_eq!(sizes.len(), 3),
+            other => panic!("expected a list, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,224 @@
+// Checks of paths taken from torrents, so files are only ever linked inside the output directory.
+// Torrents are untrusted input: their paths may contain traversal, absolute components,
+// NUL bytes or names the platform reserves.
+
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    // Torrents with unsafe paths are rejected
+    Strict,
+    // Unsafe components are renamed or dropped
+    Lenient,
+}
+
+impl Mode {
+    pub fn from_name(name: &str) -> Mode {
+        match name {
+            "lenient" => Mode::Lenient,
+            _ => Mode::Strict,
+        }
+    }
+}
+
+// Joins the components of a torrent path into a relative path.
+// Lenient mode logs each path it rewrites.
+pub fn path<I, S>(components: I, mode: Mode) -> Result<PathBuf, String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let components: Vec<String> = components
+        .into_iter()
+        .map(|c| c.as_ref().to_string_lossy().into_owned())
+        .collect();
+    let original = components.join("/");
+    let mut path = PathBuf::new();
+    let mut rewritten = false;
+    for name in &components {
+        match (check(name), mode) {
+            (None, _) => path.push(name),
+            (Some(reason), Mode::Strict) => {
+                return Err(format!("Unsafe path {:?}: {}", original, reason))
+            }
+            (Some(_), Mode::Lenient) => {
+                rewritten = true;
+                if let Some(name) = rename(name) {
+                    path.push(name);
+                }
+            }
+        }
+    }
+    if path.as_os_str().is_empty() {
+        if mode == Mode::Strict {
+            return Err(format!("Unsafe path {:?}: empty path", original));
+        }
+        rewritten = true;
+        path.push("_");
+    }
+    if rewritten {
+        eprintln!("Renamed unsafe path {:?} to {:?}", original, path);
+    }
+    Ok(path)
+}
+
+// Returns why a single component is unsafe, if it is.
+pub fn check(name: &str) -> Option<&'static str> {
+    if name.is_empty() || name == "." {
+        Some("empty component")
+    } else if name == ".." {
+        Some("parent directory")
+    } else if name.starts_with(is_separator) {
+        Some("absolute path")
+    } else if name.contains(is_separator) {
+        Some("path separator in name")
+    } else if name.contains('\0') {
+        Some("NUL byte in name")
+    } else if is_reserved(name) {
+        Some("reserved name")
+    } else {
+        None
+    }
+}
+
+// Replaces an unsafe component with a safe one, or drops it.
+fn rename(name: &str) -> Option<String> {
+    let name = name.trim_start_matches(is_separator);
+    match name {
+        // Roots and empty components don't name anything
+        "" | "." => None,
+        ".." => Some("__".to_string()),
+        _ if is_reserved(name) => Some(format!("_{}", name)),
+        _ => Some(
+            name.chars()
+                .map(|c| if is_separator(c) || c == '\0' { '_' } else { c })
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(target_family = "windows")]
+fn is_separator(c: char) -> bool {
+    c == '/' || c == '\\' || c == ':'
+}
+
+#[cfg(not(target_family = "windows"))]
+fn is_separator(c: char) -> bool {
+    c == '/'
+}
+
+// Device names that open a device instead of a file, regardless of extension.
+#[cfg(target_family = "windows")]
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    let upper = stem.to_ascii_uppercase();
+    match upper.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        _ => {
+            (upper.starts_with("COM") || upper.starts_with("LPT"))
+                && upper.len() == 4
+                && upper.as_bytes()[3].is_ascii_digit()
+                && upper.as_bytes()[3] != b'0'
+        }
+    }
+}
+
+// Other platforms have no reserved names beyond "." and "..".
+#[cfg(not(target_family = "windows"))]
+fn is_reserved(_name: &str) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{check, path, rename, Mode};
+
+    #[test]
+    fn checks_components() {
+        assert_eq!(check("track01.flac"), None);
+        assert_eq!(check("..."), None);
+        assert_eq!(check(""), Some("empty component"));
+        assert_eq!(check("."), Some("empty component"));
+        assert_eq!(check(".."), Some("parent directory"));
+        assert_eq!(check("/etc"), Some("absolute path"));
+        assert_eq!(check("a/b"), Some("path separator in name"));
+        assert_eq!(check("a\0b"), Some("NUL byte in name"));
+    }
+
+    #[cfg(target_family = "windows")]
+    #[test]
+    fn checks_reserved_names() {
+        assert_eq!(check("con"), Some("reserved name"));
+        assert_eq!(check("NUL.txt"), Some("reserved name"));
+        assert_eq!(check("COM1"), Some("reserved name"));
+        assert_eq!(check("COM0"), None);
+        assert_eq!(check("CONSOLE"), None);
+        assert_eq!(check("C:"), Some("absolute path"));
+        assert_eq!(check("a\\b"), Some("path separator in name"));
+    }
+
+    #[test]
+    fn renames_components() {
+        assert_eq!(rename(""), None);
+        assert_eq!(rename("."), None);
+        assert_eq!(rename("/"), None);
+        assert_eq!(rename(".."), Some("__".to_string()));
+        assert_eq!(rename("/etc"), Some("etc".to_string()));
+        assert_eq!(rename("a/b"), Some("a_b".to_string()));
+        assert_eq!(rename("a\0b"), Some("a_b".to_string()));
+    }
+
+    #[cfg(target_family = "windows")]
+    #[test]
+    fn renames_reserved_names() {
+        assert_eq!(rename("aux.txt"), Some("_aux.txt".to_string()));
+    }
+
+    #[test]
+    fn accepts_safe_paths() {
+        let components = ["album", "cd1", "track01.flac"];
+        assert_eq!(
+            path(components, Mode::Strict),
+            Ok(PathBuf::from("album/cd1/track01.flac"))
+        );
+    }
+
+    #[test]
+    fn rejects_unsafe_paths() {
+        for components in [
+            &["album", "..", "..", "etc", "passwd"][..],
+            &["album", "/etc", "passwd"],
+            &["album", "a\0b"],
+            &["album", ""],
+            &[],
+        ] {
+            assert!(path(components, Mode::Strict).is_err(), "{:?}", components);
+        }
+    }
+
+    #[test]
+    fn rewrites_unsafe_paths() {
+        let cases: &[(&[&str], &str)] = &[
+            (
+                &["album", "..", "..", "etc", "passwd"],
+                "album/__/__/etc/passwd",
+            ),
+            (&["ok", "/tmp", "rv", "pwn"], "ok/tmp/rv/pwn"),
+            (&["album", "a\0b"], "album/a_b"),
+            (&["album", "", ".", "track"], "album/track"),
+            (&["/"], "_"),
+            (&[], "_"),
+        ];
+        for &(components, expected) in cases {
+            assert_eq!(
+                path(components, Mode::Lenient),
+                Ok(PathBuf::from(expected)),
+                "{:?}",
+                components
+            );
+        }
+    }
+}
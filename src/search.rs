@@ -0,0 +1,238 @@
+// Search for files that match descriptors by size and content.
+// Directories are walked while pools of workers verify hashes.
+// Hard links to the same file are hashed once and reported once.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, Metadata};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+
+use multimap::MultiMap;
+use walkdir::{DirEntry, WalkDir};
+
+use super::cache::Cache;
+use super::link::{self, inode, Method};
+use super::Descriptor;
+
+pub struct SearchContext {
+    pub descriptors: Vec<Descriptor>,
+    pub by_size: MultiMap<i64, usize>,
+    pub follow_symlinks: bool,
+    pub link_method: Method,
+    pub hash_threshold: f32,
+    pub cache: Option<Cache>,
+    // Canonical output directory, which is not searched when it is inside an input directory
+    pub output: PathBuf,
+}
+
+// File found on disk that passed the hash check of the descriptor at index `file`.
+pub struct Candidate {
+    pub file: usize,
+    pub path: PathBuf,
+    // Device and inode, shared by hard links
+    id: Option<(u64, u64)>,
+}
+
+impl SearchContext {
+    // Returns why a candidate doesn't match, if it doesn't.
+    fn verify(&self, c: &Candidate) -> Option<String> {
+        let descriptor = &self.descriptors[c.file];
+        let result = File::open(&c.path).and_then(|mut file| {
+            let cache = self.cache.as_ref().map(|cache| (cache, c.path.as_path()));
+            descriptor.verify_file(&mut file, self.hash_threshold, cache)
+        });
+        match result {
+            Ok(None) => None,
+            Ok(Some(piece)) => Some(format!("Piece {} does not match", piece)),
+            Err(err) => {
+                eprintln!("{}", err);
+                Some(err.to_string())
+            }
+        }
+    }
+
+    // Whether a directory entry is the output directory, or a symbolic link into it.
+    fn is_output(&self, entry: &DirEntry, output_id: Option<(u64, u64)>) -> bool {
+        if entry.path_is_symlink() {
+            return link::canonicalize_existing(entry.path())
+                .is_ok_and(|path| path.starts_with(&self.output));
+        }
+        output_id.is_some()
+            && entry.file_type().is_dir()
+            && entry.metadata().ok().and_then(|meta| inode(&meta)) == output_id
+    }
+}
+
+// Walks the input directories and verifies candidates.
+// Each input directory is walked on its own thread,
+// and each device gets its own pool of `jobs` workers unless overridden in `device_jobs`,
+// so disks are read in parallel without seeking between many files on one disk.
+// Candidates are returned in walk order, independent of scheduling,
+// paired with the reason they were rejected if they failed to verify.
+pub fn search(
+    ctx: &Arc<SearchContext>,
+    input_dirs: &[&str],
+    jobs: usize,
+    device_jobs: &HashMap<u64, usize>,
+) -> Vec<(Candidate, Option<String>)> {
+    let (result_tx, result_rx) = channel();
+    let pools = Arc::new(Mutex::new(HashMap::<u64, Pool>::new()));
+    let walkers: Vec<_> = input_dirs
+        .iter()
+        .enumerate()
+        .map(|(i, dir)| {
+            let dir = dir.to_string();
+            let ctx = Arc::clone(ctx);
+            let pools = Arc::clone(&pools);
+            let result_tx = result_tx.clone();
+            let device_jobs = device_jobs.clone();
+            thread::spawn(move || {
+                let mut queues = HashMap::new();
+                for (n, (c, device)) in search_dir(&dir, &ctx).enumerate() {
+                    let queue = queues.entry(device).or_insert_with(|| {
+                        let mut pools = pools.lock().unwrap();
+                        let pool = pools.entry(device).or_insert_with(|| {
+                            let threads = device_jobs.get(&device).copied().unwrap_or(jobs);
+                            Pool::new(threads, &ctx, &result_tx)
+                        });
+                        pool.queue.clone()
+                    });
+                    queue.send(((i, n), c)).unwrap();
+                }
+            })
+        })
+        .collect();
+    drop(result_tx);
+
+    for walker in walkers {
+        walker.join().unwrap();
+    }
+    let pools = std::mem::take(&mut *pools.lock().unwrap());
+    for (_, pool) in pools {
+        drop(pool.queue);
+        for worker in pool.workers {
+            worker.join().unwrap();
+        }
+    }
+
+    let mut found: Vec<(Seq, Candidate, Option<String>)> = result_rx.iter().collect();
+    found.sort_by_key(|&(seq, _, _)| seq);
+    // Hard links after the first one found are the same file
+    let mut seen = HashSet::new();
+    found
+        .into_iter()
+        .filter(|(_, c, _)| c.id.is_none_or(|id| seen.insert((id, c.file))))
+        .map(|(_, c, rejection)| (c, rejection))
+        .collect()
+}
+
+// Position of a candidate in the walk, by input directory and entry.
+type Seq = (usize, usize);
+
+// Verification results by inode and descriptor, shared by the workers of a pool.
+// Hard links are on the same device, so they are verified by the same pool.
+type Verified = Mutex<HashMap<((u64, u64), usize), Arc<OnceLock<Option<String>>>>>;
+
+// Workers verifying candidates on one device.
+struct Pool {
+    queue: SyncSender<(Seq, Candidate)>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Pool {
+    fn new(
+        threads: usize,
+        ctx: &Arc<SearchContext>,
+        results: &Sender<(Seq, Candidate, Option<String>)>,
+    ) -> Pool {
+        // Bounded queue so walking doesn't run far ahead of hashing
+        let (queue, jobs) = sync_channel::<(Seq, Candidate)>(threads * 4);
+        let jobs = Arc::new(Mutex::new(jobs));
+        let verified = Arc::new(Verified::default());
+        let workers = (0..threads)
+            .map(|_| {
+                let jobs = Arc::clone(&jobs);
+                let verified = Arc::clone(&verified);
+                let results = results.clone();
+                let ctx = Arc::clone(ctx);
+                thread::spawn(move || loop {
+                    let job = jobs.lock().unwrap().recv();
+                    let (seq, c) = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    // Links being verified by another worker wait for its result
+                    let rejection = match c.id {
+                        Some(id) => {
+                            let result = Arc::clone(
+                                verified.lock().unwrap().entry((id, c.file)).or_default(),
+                            );
+                            result.get_or_init(|| ctx.verify(&c)).clone()
+                        }
+                        None => ctx.verify(&c),
+                    };
+                    results.send((seq, c, rejection)).unwrap();
+                })
+            })
+            .collect();
+        Pool { queue, workers }
+    }
+}
+
+// Device a file is stored on.
+// Devices are not exposed on all platforms, there all files share one pool.
+pub fn device(meta: &Metadata) -> u64 {
+    inode(meta).map_or(0, |(dev, _)| dev)
+}
+
+// Searches a directory at path for files that match descriptors in `by_size`.
+// If `symlinks` is enabled, files behind symbolic links are also considered.
+// The output directory and links into it are skipped, so earlier links aren't found again,
+// unless the output is the directory being searched.
+// Entries are visited in file name order so results are reproducible.
+// Candidates are paired with the device they are stored on.
+fn search_dir<'a>(
+    path: &str,
+    ctx: &'a SearchContext,
+) -> impl Iterator<Item = (Candidate, u64)> + 'a {
+    let output_id = fs::metadata(&ctx.output).ok().and_then(|meta| inode(&meta));
+    WalkDir::new(path)
+        .follow_links(ctx.follow_symlinks)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(move |entry| entry.depth() == 0 || !ctx.is_output(entry, output_id))
+        // Print and filter errors
+        .filter_map(|entry| entry.map_err(|err| eprintln!("{}", err)).ok())
+        // Ignore directories
+        .filter(|entry| entry.file_type().is_file())
+        // Get metadata
+        .filter_map(|entry| {
+            entry
+                .metadata()
+                .map_err(|err| eprintln!("{}", err))
+                .ok()
+                .map(|meta| (entry, meta))
+        })
+        // Lookup sizes to get matches, each file may match several descriptors
+        .flat_map(move |(entry, meta)| {
+            let size = meta.len();
+            let path = entry.path().to_path_buf();
+            let device = device(&meta);
+            let id = inode(&meta);
+            ctx.by_size
+                .get_vec(&(size as i64))
+                .cloned()
+                .unwrap_or_default()
+                .into_iter()
+                .map(move |file| {
+                    let c = Candidate {
+                        file,
+                        path: path.clone(),
+                        id,
+                    };
+                    (c, device)
+                })
+        })
+}
@@ -0,0 +1,587 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use sha1::{Digest, Sha1};
+
+use super::{Descriptor, Span};
+
+// Share of the budget charged for assigning a candidate, without any hashing.
+// Keeps the search bounded where results are already known.
+const ASSIGNMENT_COST: f64 = 1.0 / 256.0;
+
+// Picks a source for each descriptor from its candidates.
+// Files with pieces of their own take their first verified candidate,
+// unless several verified and the file shares pieces with others.
+// Those and files without pieces of their own are assigned
+// by a backtracking search over their candidates,
+// such that every spanning piece verifies against the chosen combination.
+// `max_combinations` limits the hashing done per search of a group of files sharing pieces,
+// in full pieces: a combination that reuses the hashed start of a piece
+// is only charged for the rest of it.
+// Files left undecided when the budget runs out are not matched.
+// Also returns which spans verified against the chosen sources.
+pub fn cross_verify(
+    descriptors: &[Descriptor],
+    spans: &[Span],
+    candidates: &[Vec<PathBuf>],
+    max_combinations: usize,
+) -> (Vec<Option<PathBuf>>, Vec<bool>) {
+    let mut solver = Solver {
+        descriptors,
+        spans,
+        candidates,
+        choices: vec![None; descriptors.len()],
+        results: HashMap::new(),
+        prefixes: HashMap::new(),
+        budget: 0.0,
+        exhausted: false,
+        best_score: 0,
+    };
+    // Files without pieces of their own are variables,
+    // and so are files with several candidates that verified their own pieces,
+    // if they share a piece, since their candidates may still differ there.
+    // The rest is fixed.
+    let mut shared = vec![false; descriptors.len()];
+    for part in spans.iter().flat_map(|span| &span.parts) {
+        shared[part.file] = true;
+    }
+    let mut vars = Vec::new();
+    for (i, d) in descriptors.iter().enumerate() {
+        if candidates[i].is_empty() {
+            continue;
+        }
+        if d.extents.is_empty() || (shared[i] && candidates[i].len() > 1) {
+            vars.push(i);
+        } else {
+            solver.choices[i] = Some(0);
+        }
+    }
+
+    for vars in group_vars(&vars, spans, descriptors.len()) {
+        // Spans are checked as soon as their last variable is assigned,
+        // unless a fixed file has no source
+        let mut checks = vec![Vec::new(); vars.len()];
+        let mut spans_of = vec![Vec::new(); vars.len()];
+        for (s, span) in spans.iter().enumerate() {
+            let members: Vec<usize> = span
+                .parts
+                .iter()
+                .filter_map(|p| vars.binary_search(&p.file).ok())
+                .collect();
+            let complete = span
+                .parts
+                .iter()
+                .all(|p| solver.choices[p.file].is_some() || vars.binary_search(&p.file).is_ok());
+            if let (Some(&last), true) = (members.last(), complete) {
+                checks[last].push(s);
+                for &member in &members {
+                    spans_of[member].push(s);
+                }
+            }
+        }
+        let mut remaining = vec![0; vars.len() + 1];
+        for i in (0..vars.len()).rev() {
+            remaining[i] = remaining[i + 1] + checks[i].len();
+        }
+        let domains = vars.iter().map(|&file| solver.domain(file)).collect();
+        let mut group = Group {
+            best: vec![None; vars.len()],
+            vars,
+            domains,
+            checks,
+            spans_of,
+            unmatched: HashMap::new(),
+            remaining,
+            allow_unmatched: false,
+        };
+        solver.budget = max_combinations as f64;
+        solver.exhausted = false;
+        solver.best_score = 0;
+        solver.search(&mut group, 0, 0, 0);
+        // Leave files unmatched only if no combination matches all of them,
+        // the second search reuses the results of the first
+        if solver.best_score < group.remaining[0] {
+            group.allow_unmatched = true;
+            solver.budget = max_combinations as f64;
+            solver.search(&mut group, 0, 0, 0);
+        }
+        solver.prefixes.clear();
+        if solver.exhausted {
+            eprintln!(
+                "Gave up matching {} files after {} combinations",
+                group.vars.len(),
+                max_combinations
+            );
+        }
+        for (&file, choice) in group.vars.iter().zip(group.best.drain(..)) {
+            // Files with pieces of their own are matched even if no candidate fits the spans
+            solver.choices[file] = match choice {
+                None if !descriptors[file].extents.is_empty() => Some(0),
+                choice => choice,
+            };
+        }
+    }
+
+    // Accept files without own pieces only if all their spans verified
+    let mut covered = vec![false; descriptors.len()];
+    let mut failed = vec![false; descriptors.len()];
+    let mut span_results = Vec::with_capacity(spans.len());
+    for (s, span) in spans.iter().enumerate() {
+        let verified = solver.verify(s).unwrap_or(false);
+        span_results.push(verified);
+        for part in &span.parts {
+            if verified {
+                covered[part.file] = true;
+            } else {
+                failed[part.file] = true;
+            }
+        }
+    }
+    let sources = descriptors
+        .iter()
+        .enumerate()
+        .map(|(i, d)| {
+            let accepted = !d.extents.is_empty() || (covered[i] && !failed[i]);
+            solver.choices[i]
+                .filter(|_| accepted)
+                .map(|c| candidates[i][c].clone())
+        })
+        .collect();
+    (sources, span_results)
+}
+
+// Splits variables into groups connected by shared spans.
+// Groups and their members are in torrent order.
+fn group_vars(vars: &[usize], spans: &[Span], file_count: usize) -> Vec<Vec<usize>> {
+    let mut parent: Vec<usize> = (0..file_count).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for span in spans {
+        let mut members = span
+            .parts
+            .iter()
+            .map(|p| p.file)
+            .filter(|f| vars.binary_search(f).is_ok());
+        if let Some(first) = members.next() {
+            for other in members {
+                let (a, b) = (root(&mut parent, first), root(&mut parent, other));
+                parent[b] = a;
+            }
+        }
+    }
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_of = HashMap::new();
+    for &var in vars {
+        let r = root(&mut parent, var);
+        let index = *group_of.entry(r).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[index].push(var);
+    }
+    groups
+}
+
+// Files connected by shared spans, searched together.
+struct Group {
+    vars: Vec<usize>,
+    // Candidate choices for each variable, see `Solver::domain`
+    domains: Vec<Vec<Option<usize>>>,
+    // `checks[i]` holds the spans completed by assigning `vars[i]`
+    checks: Vec<Vec<usize>>,
+    // `spans_of[i]` holds the checked spans that `vars[i]` is part of
+    spans_of: Vec<Vec<usize>>,
+    // Number of parts left unmatched, by span
+    unmatched: HashMap<usize, usize>,
+    // Number of spans completed at or after each variable, plus a trailing zero
+    remaining: Vec<usize>,
+    // Choices with the most verified spans so far
+    best: Vec<Option<usize>>,
+    // Whether variables may be left unmatched
+    allow_unmatched: bool,
+}
+
+struct Solver<'a> {
+    descriptors: &'a [Descriptor],
+    spans: &'a [Span],
+    candidates: &'a [Vec<PathBuf>],
+    // Index of the chosen candidate for each file
+    choices: Vec<Option<usize>>,
+    // Memoized span results by span index and candidates of its parts
+    results: HashMap<(usize, Vec<usize>), bool>,
+    // Hash state after the first parts of a span, by span index and candidates of those parts.
+    // Variables are assigned in torrent order, like the parts of a span,
+    // so combinations that only differ in the last parts share these.
+    prefixes: HashMap<(usize, Vec<usize>), Sha1>,
+    // Pieces left to hash in the current group
+    budget: f64,
+    exhausted: bool,
+    // Verified spans of the best choices in the current group
+    best_score: usize,
+}
+
+impl<'a> Solver<'a> {
+    // Verifies a span against the current choices.
+    // Returns `None` if a part has no file chosen.
+    fn verify(&mut self, s: usize) -> Option<bool> {
+        let span = &self.spans[s];
+        let key: Vec<usize> = span
+            .parts
+            .iter()
+            .map(|p| self.choices[p.file])
+            .collect::<Option<_>>()?;
+        if let Some(&result) = self.results.get(&(s, key.clone())) {
+            return Some(result);
+        }
+        // Continue from the longest prefix hashed before
+        let mut start = key.len() - 1;
+        while start > 0 && !self.prefixes.contains_key(&(s, key[..start].to_vec())) {
+            start -= 1;
+        }
+        let mut state = match start {
+            0 => Sha1::new(),
+            _ => self.prefixes[&(s, key[..start].to_vec())].clone(),
+        };
+        let mut complete = true;
+        let mut hashed = 0;
+        for (i, part) in span.parts.iter().enumerate().skip(start) {
+            let path = &self.candidates[part.file][key[i]];
+            complete = part.hash(path, &mut state).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                false
+            });
+            if !complete {
+                break;
+            }
+            hashed += part.size + part.padding;
+            if i + 1 < key.len() {
+                self.prefixes.insert((s, key[..=i].to_vec()), state.clone());
+            }
+        }
+        self.budget -= hashed as f64 / span.size() as f64;
+        let result = complete && state.result().as_slice() == &span.hash[..];
+        self.results.insert((s, key), result);
+        Some(result)
+    }
+
+    // Spans that can't be checked yet don't constrain the search.
+    fn check(&mut self, s: usize) -> bool {
+        self.verify(s).unwrap_or(true)
+    }
+
+    // Candidates of a file that pass all spans shared only with fixed files.
+    // Candidates named like the torrent file are tried first,
+    // leaving the file unmatched is always the last option.
+    fn domain(&mut self, file: usize) -> Vec<Option<usize>> {
+        let unary: Vec<usize> = (0..self.spans.len())
+            .filter(|&s| {
+                let parts = &self.spans[s].parts;
+                parts.iter().any(|p| p.file == file)
+                    && parts
+                        .iter()
+                        .all(|p| p.file == file || self.choices[p.file].is_some())
+            })
+            .collect();
+        let mut domain = Vec::new();
+        for c in 0..self.candidates[file].len() {
+            self.choices[file] = Some(c);
+            if unary.iter().all(|&s| self.check(s)) {
+                domain.push(Some(c));
+            }
+        }
+        self.choices[file] = None;
+        let name = self.descriptors[file].path.file_name();
+        domain.sort_by_key(|c| self.candidates[file][c.unwrap()].file_name() != name);
+        domain.push(None);
+        domain
+    }
+
+    // Branch and bound search assigning `group.vars[depth..]`,
+    // maximizing the number of verified spans.
+    // Spans that fail to verify prune the branch,
+    // spans with an unmatched part neither fail nor count.
+    // `lost` is the number of spans left to check that have an unmatched part.
+    // Once the budget is spent, remaining variables are left unmatched.
+    // Assignments are charged for the pieces they hash, see `cross_verify`.
+    fn search(&mut self, group: &mut Group, depth: usize, score: usize, lost: usize) {
+        if depth > 0 && score + group.remaining[depth] - lost <= self.best_score {
+            return;
+        }
+        if depth == group.vars.len() {
+            self.best_score = score;
+            for (best, &file) in group.best.iter_mut().zip(&group.vars) {
+                *best = self.choices[file];
+            }
+            return;
+        }
+        let var = group.vars[depth];
+        for i in 0..group.domains[depth].len() {
+            let value = group.domains[depth][i];
+            if value.is_none() && !group.allow_unmatched {
+                continue;
+            }
+            if value.is_some() {
+                if self.budget <= 0.0 {
+                    self.exhausted = true;
+                    continue;
+                }
+                self.budget -= ASSIGNMENT_COST;
+            }
+            self.choices[var] = value;
+            let mut lost = lost;
+            if value.is_none() {
+                for &s in &group.spans_of[depth] {
+                    let unmatched = group.unmatched.entry(s).or_insert(0);
+                    *unmatched += 1;
+                    if *unmatched == 1 {
+                        lost += 1;
+                    }
+                }
+            }
+            let mut verified = 0;
+            let mut failed = false;
+            for &s in &group.checks[depth] {
+                match self.verify(s) {
+                    Some(true) => verified += 1,
+                    Some(false) => {
+                        failed = true;
+                        break;
+                    }
+                    None => lost -= 1,
+                }
+            }
+            if !failed {
+                self.search(group, depth + 1, score + verified, lost);
+            }
+            if value.is_none() {
+                for &s in &group.spans_of[depth] {
+                    *group.unmatched.get_mut(&s).unwrap() -= 1;
+                }
+            }
+            // Stop once every span verified
+            if self.best_score == group.remaining[0] {
+                break;
+            }
+        }
+        self.choices[var] = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use sha1::{Digest, Sha1};
+
+    use super::{cross_verify, group_vars};
+    use crate::{Descriptor, Extent, Part, PieceHash, Span};
+
+    // Pseudo random content, distinct for each seed.
+    fn content(seed: u64, size: usize) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "find-torrent-data-solver-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Descriptors and spans of a torrent with the given files.
+    fn layout(dir: &Path, files: &[Vec<u8>], piece_length: usize) -> (Vec<Descriptor>, Vec<Span>) {
+        let mut descriptors: Vec<Descriptor> = files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| Descriptor {
+                path: dir.join(format!("torrent-{}", i)),
+                size: file.len() as i64,
+                extents: Vec::new(),
+            })
+            .collect();
+        let data = files.concat();
+        let mut starts = vec![0];
+        for file in files {
+            starts.push(starts.last().unwrap() + file.len());
+        }
+        let mut spans = Vec::new();
+        for (piece, start) in (0..data.len()).step_by(piece_length).enumerate() {
+            let end = (start + piece_length).min(data.len());
+            let hash = Sha1::digest(&data[start..end]);
+            let parts: Vec<Part> = (0..files.len())
+                .filter(|&f| starts[f] < end && starts[f + 1] > start)
+                .map(|f| {
+                    let offset = start.max(starts[f]) - starts[f];
+                    let size = end.min(starts[f + 1]) - starts[f] - offset;
+                    Part {
+                        file: f,
+                        offset: offset as i64,
+                        size: size as i64,
+                        padding: 0,
+                    }
+                })
+                .collect();
+            if parts.len() == 1 {
+                let mut sha1 = [0; 20];
+                sha1.copy_from_slice(&hash);
+                descriptors[parts[0].file].extents.push(Extent {
+                    piece,
+                    offset: parts[0].offset,
+                    size: parts[0].size,
+                    padding: 0,
+                    hash: PieceHash::Sha1(sha1),
+                });
+            } else {
+                let mut span = Span {
+                    piece,
+                    hash: [0; 20],
+                    parts,
+                };
+                span.hash.copy_from_slice(&hash);
+                spans.push(span);
+            }
+        }
+        (descriptors, spans)
+    }
+
+    // Writes the candidate files, returns their paths.
+    fn write(dir: &Path, files: &[Vec<u8>]) -> Vec<PathBuf> {
+        files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| {
+                let path = dir.join(format!("candidate-{}", i));
+                fs::write(&path, file).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    fn span(files: &[usize]) -> Span {
+        Span {
+            piece: 0,
+            hash: [0; 20],
+            parts: files
+                .iter()
+                .map(|&file| Part {
+                    file,
+                    offset: 0,
+                    size: 1,
+                    padding: 0,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn groups_connected_vars() {
+        let spans = [span(&[0, 1]), span(&[1, 2]), span(&[3, 4, 5]), span(&[6])];
+        let groups = group_vars(&[0, 1, 2, 3, 5, 6, 7], &spans, 8);
+        // File 4 is fixed, but still connects 3 and 5
+        assert_eq!(groups, vec![vec![0, 1, 2], vec![3, 5], vec![6], vec![7]]);
+    }
+
+    #[test]
+    fn groups_ignore_fixed_files() {
+        let spans = [span(&[0, 1]), span(&[1, 2])];
+        let groups = group_vars(&[0, 2], &spans, 3);
+        assert_eq!(groups, vec![vec![0], vec![2]]);
+    }
+
+    #[test]
+    fn picks_the_verifying_combination() {
+        let dir = temp_dir("combination");
+        let files: Vec<Vec<u8>> = (0..3).map(|i| content(i, 5000)).collect();
+        let (descriptors, spans) = layout(&dir, &files, 16384);
+        let mut pool = files.clone();
+        pool.extend((10..13).map(|i| content(i, 5000)));
+        let paths = write(&dir, &pool);
+        // Decoys first
+        let candidates = vec![paths.iter().rev().cloned().collect(); 3];
+        let (sources, verified) = cross_verify(&descriptors, &spans, &candidates, 10000);
+        assert_eq!(
+            sources,
+            vec![
+                Some(paths[0].clone()),
+                Some(paths[1].clone()),
+                Some(paths[2].clone()),
+            ]
+        );
+        assert_eq!(verified, vec![true]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reuses_hashed_prefixes() {
+        // Each piece spans up to four files with 16 candidates each,
+        // more than the budget allows when whole pieces are hashed
+        let dir = temp_dir("prefixes");
+        let files: Vec<Vec<u8>> = (0..8).map(|i| content(i, 5000)).collect();
+        let (descriptors, spans) = layout(&dir, &files, 16384);
+        let mut pool: Vec<Vec<u8>> = (10..18).map(|i| content(i, 5000)).collect();
+        pool.extend(files.iter().rev().cloned());
+        let paths = write(&dir, &pool);
+        let candidates = vec![paths.clone(); 8];
+        let (sources, verified) = cross_verify(&descriptors, &spans, &candidates, 10000);
+        let expected: Vec<_> = (0..8).map(|i| Some(paths[15 - i].clone())).collect();
+        assert_eq!(sources, expected);
+        assert_eq!(verified, vec![true; 3]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn searches_files_with_pieces_of_their_own() {
+        // A preallocated partial download has the whole pieces but not the shared tail
+        let dir = temp_dir("own");
+        let files = vec![content(1, 40000), content(2, 1000)];
+        let (descriptors, spans) = layout(&dir, &files, 16384);
+        assert_eq!(descriptors[0].extents.len(), 2);
+        let mut partial = files[0].clone();
+        for byte in &mut partial[32768..] {
+            *byte = 0;
+        }
+        let paths = write(&dir, &[partial, files[0].clone(), files[1].clone()]);
+        let candidates = vec![paths[..2].to_vec(), vec![paths[2].clone()]];
+        let (sources, verified) = cross_verify(&descriptors, &spans, &candidates, 10000);
+        assert_eq!(
+            sources,
+            vec![Some(paths[1].clone()), Some(paths[2].clone())]
+        );
+        assert_eq!(verified, vec![true]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn leaves_files_of_failed_pieces_unmatched() {
+        let dir = temp_dir("unmatched");
+        let files: Vec<Vec<u8>> = (0..4).map(|i| content(i, 5000)).collect();
+        let (descriptors, spans) = layout(&dir, &files, 10000);
+        // The second file is missing
+        let pool = vec![files[3].clone(), files[2].clone(), files[0].clone()];
+        let paths = write(&dir, &pool);
+        let candidates = vec![paths.clone(); 4];
+        let (sources, verified) = cross_verify(&descriptors, &spans, &candidates, 10000);
+        let expected = vec![None, None, Some(paths[1].clone()), Some(paths[0].clone())];
+        assert_eq!(sources, expected);
+        assert_eq!(verified, vec![false, true]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
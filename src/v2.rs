@@ -0,0 +1,487 @@
+// BitTorrent v2 (BEP 52) metadata.
+// Files are hashed independently with SHA-256 merkle trees over 16 KiB blocks,
+// so every file can be verified without looking at its neighbours.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Read, Result as IOResult};
+use std::path::Path;
+
+use lava_torrent::bencode::BencodeElem;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use super::names::Naming;
+use super::{Descriptor, Extent, FileEntry, Layout, PieceHash, TorrentInfo};
+
+const BLOCK_SIZE: i64 = 16384;
+
+// Builds descriptors from the file tree and piece layers of a v2 or hybrid torrent.
+// `raw_info` is the encoded info dictionary the info hashes are computed from.
+// Returns `None` if the torrent has no v2 metadata.
+pub fn make_descriptors(
+    root: &BencodeElem,
+    raw_info: &[u8],
+    want_prefix: &Path,
+    naming: &Naming,
+) -> Result<Option<(Layout, TorrentInfo)>, Box<dyn Error>> {
+    let root = match root {
+        BencodeElem::Dictionary(root) => root,
+        _ => return Ok(None),
+    };
+    let info = match root.get("info") {
+        Some(BencodeElem::Dictionary(info)) => info,
+        _ => return Ok(None),
+    };
+    match info.get("meta version") {
+        Some(BencodeElem::Integer(2)) => {}
+        _ => return Ok(None),
+    }
+    let tree = match info.get("file tree") {
+        Some(BencodeElem::Dictionary(tree)) => tree,
+        _ => return Err(r#""file tree" is missing or not a dictionary"#.into()),
+    };
+    let name = match info.get("name") {
+        Some(BencodeElem::String(name)) => name,
+        _ => return Err(r#""name" is missing or not a string"#.into()),
+    };
+    let piece_length = match info.get("piece length") {
+        Some(&BencodeElem::Integer(len)) if len >= BLOCK_SIZE && len.count_ones() == 1 => len,
+        _ => return Err(r#""piece length" is not a power of two of at least 16 KiB"#.into()),
+    };
+    let layers = piece_layers(root.get("piece layers"));
+
+    let mut files = Vec::new();
+    collect_files(tree, &[], &mut files)?;
+    // Single file torrents hold their only file at the top of the tree
+    let single_file = match tree.values().next() {
+        Some(BencodeElem::Dictionary(node)) => tree.len() == 1 && node.contains_key(""),
+        _ => false,
+    };
+
+    // Hybrid torrents also carry v1 pieces and have a v1 info hash
+    let mut info_hash = [0u8; 20];
+    if info.contains_key("pieces") {
+        info_hash.copy_from_slice(Sha1::digest(raw_info).as_slice());
+    }
+    let mut torrent_info = TorrentInfo {
+        name: name.clone(),
+        info_hash,
+        info_hash_v2: Some(hash(raw_info)),
+        piece_length,
+        piece_count: 0,
+        total_size: 0,
+        files: Vec::new(),
+    };
+
+    let mut descriptors = Vec::new();
+    let mut names = naming.translator();
+    let file_count = files.len();
+    for (index, (mut components, length, pieces_root)) in files.into_iter().enumerate() {
+        if !single_file {
+            components.insert(0, name);
+        }
+        let (path, renamed_from) = names.file(&components)?;
+        let path = want_prefix.join(path);
+        // Pieces are aligned to the start of each file
+        let first_piece = torrent_info.piece_count;
+        torrent_info.piece_count += ((length + piece_length - 1) / piece_length) as usize;
+        // Files but the last are padded to a piece boundary in the v1 view of hybrids
+        if length > 0 {
+            torrent_info.total_size = first_piece as i64 * piece_length + length;
+        }
+        torrent_info.files.push(FileEntry {
+            path: path.clone(),
+            length,
+            pad: false,
+            renamed_from,
+        });
+        // The v1 view of hybrids has pad files, and clients index files with them
+        let padding = (piece_length - length % piece_length) % piece_length;
+        if padding > 0 && index + 1 < file_count {
+            let (pad_path, renamed_from) = names.file(&[name, ".pad", &padding.to_string()])?;
+            torrent_info.files.push(FileEntry {
+                path: want_prefix.join(pad_path),
+                length: padding,
+                pad: true,
+                renamed_from,
+            });
+        }
+        // Empty files have no content to search for
+        if length == 0 {
+            continue;
+        }
+        let blocks = ((length + BLOCK_SIZE - 1) / BLOCK_SIZE) as usize;
+        let whole_file = vec![Extent {
+            piece: first_piece,
+            offset: 0,
+            size: length,
+            padding: 0,
+            hash: PieceHash::Merkle(pieces_root, blocks.next_power_of_two()),
+        }];
+        let extents = if length <= piece_length {
+            whole_file
+        } else if let Some(layer) = layers.get(&pieces_root[..]) {
+            let pieces = ((length + piece_length - 1) / piece_length) as usize;
+            if layer.len() != pieces * 32 {
+                return Err(format!("Piece layer of {} has wrong size", path.display()).into());
+            }
+            layer
+                .chunks(32)
+                .enumerate()
+                .map(|(i, hash)| {
+                    let offset = i as i64 * piece_length;
+                    let mut array = [0u8; 32];
+                    array.copy_from_slice(hash);
+                    Extent {
+                        piece: first_piece + i,
+                        offset,
+                        size: piece_length.min(length - offset),
+                        padding: 0,
+                        hash: PieceHash::Merkle(array, (piece_length / BLOCK_SIZE) as usize),
+                    }
+                })
+                .collect()
+        } else {
+            // Without a piece layer, the file can only be verified as a whole
+            whole_file
+        };
+        descriptors.push(Descriptor {
+            path,
+            size: length,
+            extents,
+        });
+    }
+    let layout = Layout {
+        descriptors,
+        spans: vec![],
+    };
+    Ok(Some((layout, torrent_info)))
+}
+
+// Walks the file tree in torrent order and collects path components,
+// length and pieces root of each file.
+fn collect_files<'a>(
+    tree: &'a HashMap<String, BencodeElem>,
+    prefix: &[&'a str],
+    files: &mut Vec<(Vec<&'a str>, i64, [u8; 32])>,
+) -> Result<(), Box<dyn Error>> {
+    let mut names: Vec<&String> = tree.keys().collect();
+    names.sort();
+    for name in names {
+        let mut path = prefix.to_vec();
+        path.push(name);
+        let node = match &tree[name] {
+            BencodeElem::Dictionary(node) => node,
+            _ => return Err(format!("{} is not a dictionary", path.join("/")).into()),
+        };
+        // Files are marked by an entry with an empty name
+        let file = match node.get("") {
+            Some(BencodeElem::Dictionary(file)) => file,
+            Some(_) => return Err(format!("{} is not a dictionary", path.join("/")).into()),
+            None => {
+                collect_files(node, &path, files)?;
+                continue;
+            }
+        };
+        let length = match file.get("length") {
+            Some(&BencodeElem::Integer(len)) if len >= 0 => len,
+            _ => return Err(format!("{} has no valid length", path.join("/")).into()),
+        };
+        let mut pieces_root = [0u8; 32];
+        if length > 0 {
+            match file.get("pieces root").and_then(as_bytes) {
+                Some(hash) if hash.len() == 32 => pieces_root.copy_from_slice(hash),
+                _ => return Err(format!("{} has no valid pieces root", path.join("/")).into()),
+            }
+        }
+        files.push((path, length, pieces_root));
+    }
+    Ok(())
+}
+
+// Maps pieces roots to the concatenated piece hashes of their files.
+fn piece_layers(layers: Option<&BencodeElem>) -> HashMap<&[u8], &[u8]> {
+    match layers {
+        Some(BencodeElem::RawDictionary(layers)) => layers
+            .iter()
+            .filter_map(|(k, v)| Some((&k[..], as_bytes(v)?)))
+            .collect(),
+        Some(BencodeElem::Dictionary(layers)) => layers
+            .iter()
+            .filter_map(|(k, v)| Some((k.as_bytes(), as_bytes(v)?)))
+            .collect(),
+        _ => HashMap::new(),
+    }
+}
+
+// Byte strings that happen to be valid UTF-8 are decoded as strings.
+fn as_bytes(elem: &BencodeElem) -> Option<&[u8]> {
+    match elem {
+        BencodeElem::Bytes(bytes) => Some(bytes),
+        BencodeElem::String(string) => Some(string.as_bytes()),
+        _ => None,
+    }
+}
+
+// Computes the merkle root over the blocks of `size` bytes from `reader`,
+// padded with zero hashes to `leaves` blocks.
+// Returns `None` if fewer than `size` bytes could be read.
+pub fn merkle_root<R: Read>(reader: R, size: i64, leaves: usize) -> IOResult<Option<[u8; 32]>> {
+    let mut reader = reader.take(size as u64);
+    let mut hashes = Vec::with_capacity(leaves);
+    let mut block = Vec::with_capacity(BLOCK_SIZE as usize);
+    let mut bytes_hashed = 0i64;
+    loop {
+        block.clear();
+        (&mut reader)
+            .take(BLOCK_SIZE as u64)
+            .read_to_end(&mut block)?;
+        if block.is_empty() {
+            break;
+        }
+        bytes_hashed += block.len() as i64;
+        hashes.push(hash(&block));
+    }
+    if bytes_hashed != size || hashes.len() > leaves {
+        return Ok(None);
+    }
+    hashes.resize(leaves, [0u8; 32]);
+    while hashes.len() > 1 {
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| hash(&[pair[0], pair[1]].concat()))
+            .collect();
+    }
+    Ok(hashes.first().copied())
+}
+
+fn hash(data: &[u8]) -> [u8; 32] {
+    let mut array = [0u8; 32];
+    array.copy_from_slice(Sha256::digest(data).as_slice());
+    array
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use lava_torrent::bencode::BencodeElem;
+    use sha2::{Digest, Sha256};
+
+    use super::{make_descriptors, merkle_root, BLOCK_SIZE};
+    use crate::names::Naming;
+    use crate::sanitize::Mode;
+    use crate::PieceHash;
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        let mut array = [0u8; 32];
+        array.copy_from_slice(Sha256::digest(data).as_slice());
+        array
+    }
+
+    fn content(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    // Merkle root over the blocks of `data`, padded with zero hashes to `leaves`.
+    fn reference_root(data: &[u8], leaves: usize) -> [u8; 32] {
+        let mut layer: Vec<[u8; 32]> = data.chunks(BLOCK_SIZE as usize).map(sha256).collect();
+        layer.resize(leaves, [0u8; 32]);
+        while layer.len() > 1 {
+            layer = layer
+                .chunks(2)
+                .map(|pair| {
+                    let mut both = pair[0].to_vec();
+                    both.extend_from_slice(&pair[1]);
+                    sha256(&both)
+                })
+                .collect();
+        }
+        layer[0]
+    }
+
+    // A v2 torrent with a single file named "file".
+    fn torrent(data: &[u8], piece_length: i64) -> BencodeElem {
+        let blocks = data.len().div_ceil(BLOCK_SIZE as usize);
+        let pieces_root = reference_root(data, blocks.next_power_of_two());
+        let mut file = HashMap::new();
+        file.insert(
+            "length".to_string(),
+            BencodeElem::Integer(data.len() as i64),
+        );
+        file.insert(
+            "pieces root".to_string(),
+            BencodeElem::Bytes(pieces_root.to_vec()),
+        );
+        let mut node = HashMap::new();
+        node.insert(String::new(), BencodeElem::Dictionary(file));
+        let mut tree = HashMap::new();
+        tree.insert("file".to_string(), BencodeElem::Dictionary(node));
+        let mut info = HashMap::new();
+        info.insert("meta version".to_string(), BencodeElem::Integer(2));
+        info.insert("name".to_string(), BencodeElem::String("file".into()));
+        info.insert(
+            "piece length".to_string(),
+            BencodeElem::Integer(piece_length),
+        );
+        info.insert("file tree".to_string(), BencodeElem::Dictionary(tree));
+        let mut root = HashMap::new();
+        root.insert("info".to_string(), BencodeElem::Dictionary(info));
+        // Only files longer than a piece have a piece layer
+        if data.len() as i64 > piece_length {
+            let leaves = (piece_length / BLOCK_SIZE) as usize;
+            let layer: Vec<u8> = data
+                .chunks(piece_length as usize)
+                .flat_map(|piece| reference_root(piece, leaves).to_vec())
+                .collect();
+            let mut layers = HashMap::new();
+            layers.insert(pieces_root.to_vec(), BencodeElem::Bytes(layer));
+            root.insert(
+                "piece layers".to_string(),
+                BencodeElem::RawDictionary(layers),
+            );
+        }
+        BencodeElem::Dictionary(root)
+    }
+
+    // A v2 torrent named "dir" with the given files and no piece layers.
+    fn multi_file(files: &[(&str, usize)], piece_length: i64) -> BencodeElem {
+        let mut tree = HashMap::new();
+        for &(name, size) in files {
+            let data = content(size);
+            let blocks = size.div_ceil(BLOCK_SIZE as usize);
+            let mut file = HashMap::new();
+            file.insert("length".to_string(), BencodeElem::Integer(size as i64));
+            file.insert(
+                "pieces root".to_string(),
+                BencodeElem::Bytes(reference_root(&data, blocks.next_power_of_two()).to_vec()),
+            );
+            let mut node = HashMap::new();
+            node.insert(String::new(), BencodeElem::Dictionary(file));
+            tree.insert(name.to_string(), BencodeElem::Dictionary(node));
+        }
+        let mut info = HashMap::new();
+        info.insert("meta version".to_string(), BencodeElem::Integer(2));
+        info.insert("name".to_string(), BencodeElem::String("dir".into()));
+        info.insert(
+            "piece length".to_string(),
+            BencodeElem::Integer(piece_length),
+        );
+        info.insert("file tree".to_string(), BencodeElem::Dictionary(tree));
+        let mut root = HashMap::new();
+        root.insert("info".to_string(), BencodeElem::Dictionary(info));
+        BencodeElem::Dictionary(root)
+    }
+
+    // Extents of the single file, as offset, size and merkle leaves,
+    // after checking their hashes against the file content.
+    fn extents(data: &[u8], piece_length: i64) -> Vec<(i64, i64, usize)> {
+        let naming = Naming {
+            mode: Mode::Strict,
+            policies: Vec::new(),
+        };
+        let root = torrent(data, piece_length);
+        let (layout, info) = make_descriptors(&root, b"", Path::new("out"), &naming)
+            .unwrap()
+            .unwrap();
+        assert_eq!(layout.descriptors.len(), 1);
+        assert_eq!(info.total_size, data.len() as i64);
+        let descriptor = &layout.descriptors[0];
+        assert_eq!(descriptor.size, data.len() as i64);
+        descriptor
+            .extents
+            .iter()
+            .map(|extent| {
+                let start = extent.offset as usize;
+                let piece = &data[start..start + extent.size as usize];
+                let digest = extent.hash.digest(piece, extent.size).unwrap();
+                assert_eq!(digest.as_deref(), Some(extent.hash.as_bytes()));
+                match extent.hash {
+                    PieceHash::Merkle(_, leaves) => (extent.offset, extent.size, leaves),
+                    PieceHash::Sha1(_) => panic!("v2 extent with a SHA-1 hash"),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn root_of_a_single_block() {
+        let data = content(1000);
+        assert_eq!(
+            merkle_root(&data[..], 1000, 1).unwrap(),
+            Some(sha256(&data))
+        );
+    }
+
+    #[test]
+    fn root_pads_to_leaves() {
+        // Three blocks, the last one short, padded to four leaves
+        let data = content(40000);
+        let blocks: Vec<[u8; 32]> = data.chunks(16384).map(sha256).collect();
+        let left = sha256(&[blocks[0], blocks[1]].concat());
+        let right = sha256(&[blocks[2], [0u8; 32]].concat());
+        let expected = sha256(&[left, right].concat());
+        assert_eq!(merkle_root(&data[..], 40000, 4).unwrap(), Some(expected));
+        assert_eq!(reference_root(&data, 4), expected);
+    }
+
+    #[test]
+    fn root_needs_all_bytes() {
+        let data = content(1000);
+        assert_eq!(merkle_root(&data[..], 2000, 1).unwrap(), None);
+        // More blocks than leaves can't match
+        let data = content(40000);
+        assert_eq!(merkle_root(&data[..], 40000, 2).unwrap(), None);
+    }
+
+    #[test]
+    fn file_shorter_than_a_block() {
+        assert_eq!(extents(&content(1000), 65536), vec![(0, 1000, 1)]);
+    }
+
+    #[test]
+    fn file_shorter_than_a_piece() {
+        // Three blocks are padded to the next power of two, not to the piece
+        assert_eq!(extents(&content(40000), 262144), vec![(0, 40000, 4)]);
+    }
+
+    #[test]
+    fn file_of_several_pieces() {
+        // The short last piece is still padded to the leaves of a whole piece
+        assert_eq!(
+            extents(&content(80000), 32768),
+            vec![(0, 32768, 2), (32768, 32768, 2), (65536, 14464, 2)]
+        );
+    }
+
+    #[test]
+    fn files_are_padded_to_pieces() {
+        let naming = Naming {
+            mode: Mode::Strict,
+            policies: Vec::new(),
+        };
+        let root = multi_file(&[("a", 1000), ("b", 16384), ("c", 500)], 16384);
+        let (_, info) = make_descriptors(&root, b"", Path::new("out"), &naming)
+            .unwrap()
+            .unwrap();
+        let files: Vec<_> = info
+            .files
+            .iter()
+            .map(|file| (file.path.to_str().unwrap(), file.length, file.pad))
+            .collect();
+        // The last file is not padded
+        assert_eq!(
+            files,
+            vec![
+                ("out/dir/a", 1000, false),
+                ("out/dir/.pad/15384", 15384, true),
+                ("out/dir/b", 16384, false),
+                ("out/dir/c", 500, false),
+            ]
+        );
+        assert_eq!(info.piece_count, 3);
+        assert_eq!(info.total_size, 2 * 16384 + 500);
+    }
+}